@@ -1,181 +1,698 @@
 use crate::{
-    calculations::{calculate_genre_months, calculate_year, largest_value_hashmap},
-    imageprocessing, lfm, spotify,
+    cache::{CacheType, MetadataCache},
+    cacheadmin::AdminToken,
+    calculations::{
+        calculate_genre_periods, calculate_listens_year, calculate_year, largest_value_hashmap,
+    },
+    daterange::{self, DateRange, Period, Periods},
+    defaults::Defaults,
+    error::WrappedErrors,
+    imageprocessing,
+    imageresponse::{ImageEncoding, ImageResponse},
+    jobs::{JobState, JobStatus, Jobs, Progress},
+    lfm, lfmexport,
+    listenstore::{self, StoredSource},
+    metadata::{ArtistRef, MetadataChain, TrackRef},
+    scrobblerlog,
+    source::ListenSource,
+    spotify::SharedSpotify,
+    spotifyhistory,
+    stats::{calculate_stats, Stats},
 };
 use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono_tz::Tz;
 use image::{DynamicImage, ImageFormat, ImageReader};
-use itertools::Itertools;
+use rocket::{
+    data::{Data, ToByteUnit},
+    response::stream::{Event, EventStream},
+    serde::json::Json,
+    State,
+};
 use serde_json::{json, Value};
-use std::io::Cursor;
+use std::{collections::HashMap, error::Error, io::Cursor};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
 
-fn img_to_response(img: DynamicImage) -> Value {
+fn img_to_b64(img: &DynamicImage) -> String {
     let mut buffer = Cursor::new(Vec::new());
     let _ = img.write_to(&mut buffer, ImageFormat::Png);
-    let encoded_image = buffer.get_ref().clone();
-    let b64 = STANDARD.encode(encoded_image);
-    json!({ "image": b64 })
+    STANDARD.encode(buffer.get_ref())
+}
+
+fn img_to_response(img: DynamicImage) -> Value {
+    json!({ "image": img_to_b64(&img) })
 }
 
 fn img_mins_to_response(img: DynamicImage, minutes: i64) -> Value {
-    let mut buffer = Cursor::new(Vec::new());
-    let _ = img.write_to(&mut buffer, ImageFormat::Png);
-    let encoded_image = buffer.get_ref().clone();
-    let b64 = STANDARD.encode(encoded_image);
-    json!({ "image": b64, "minutes": minutes })
+    json!({ "image": img_to_b64(&img), "minutes": minutes })
+}
+
+fn minutes_image(
+    total: HashMap<i64, i64>,
+    range: DateRange,
+) -> Result<(DynamicImage, i64), WrappedErrors> {
+    let total_minutes = ((total.values().sum::<i64>()) / 1000) / 60;
+    let busiest = largest_value_hashmap(&total);
+    let busiest_time = (busiest[1] / 1000) / 60;
+
+    let img = imageprocessing::minutes_listened(total_minutes, busiest[0], busiest_time, range.tz)
+        .map_err(render_error)?;
+    Ok((img, total_minutes))
 }
 
 fn imgs_to_response(imgs: Vec<DynamicImage>) -> Value {
-    let mut encoded = Vec::with_capacity(imgs.capacity());
-    for i in imgs {
-        let mut buffer = Cursor::new(Vec::new());
-        let _ = i.write_to(&mut buffer, ImageFormat::Png);
-        let encoded_image = buffer.get_ref().clone();
-        encoded.push(STANDARD.encode(encoded_image));
-    }
+    let encoded = imgs.iter().map(img_to_b64).collect::<Vec<_>>();
     json!({ "images": encoded })
 }
 
+// imageprocessing hands back Box<dyn Error>, anything failing in there is a render error
+fn render_error(e: Box<dyn Error>) -> WrappedErrors {
+    WrappedErrors::Render(e.to_string())
+}
+
+async fn fetch_image(url: &str) -> Result<DynamicImage, WrappedErrors> {
+    if url.is_empty() {
+        return Err(WrappedErrors::NotFound("no image found".to_string()));
+    }
+    let bytes = reqwest::get(url).await?.error_for_status()?.bytes().await?;
+    ImageReader::new(Cursor::new(&bytes))
+        .with_guessed_format()
+        .map_err(|e| WrappedErrors::Upstream(e.to_string()))?
+        .decode()
+        .map_err(|e| WrappedErrors::Upstream(e.to_string()))
+}
+
+// the title half of "Artist - Title"
+fn track_title(track: &str) -> &str {
+    track.split_once(" - ").map_or(track, |(_, title)| title)
+}
+
+// spotify is one provider of many, without credentials the others are still asked
+// spotify being down only costs its lookups, the other providers can still answer
+async fn metadata_chain(shared_spotify: &SharedSpotify) -> Result<MetadataChain, WrappedErrors> {
+    let spotify = shared_spotify.client().await.unwrap_or_else(|e| {
+        log::warn!("leaving spotify out of the metadata lookups: {}", e);
+        None
+    });
+    MetadataChain::from_env(spotify)
+}
+
+fn placeholder_image() -> Result<DynamicImage, WrappedErrors> {
+    Ok(ImageReader::open(Defaults::BLACK_IMAGE)?.decode()?)
+}
+
+// one missing picture shouldnt cost the whole slide, it gets the placeholder instead
+fn or_placeholder(
+    image: Result<Option<DynamicImage>, WrappedErrors>,
+    what: &str,
+) -> Result<DynamicImage, WrappedErrors> {
+    match image {
+        Ok(Some(img)) => Ok(img),
+        Ok(None) => placeholder_image(),
+        Err(e) => {
+            log::warn!("couldnt fetch {}: {}", what, e);
+            placeholder_image()
+        }
+    }
+}
+
+// track is "Artist - Title" like everywhere else, tracks nobody has a cover for get a black one
+async fn fetch_song_cover(
+    metadata: &MetadataChain,
+    track: &str,
+) -> Result<DynamicImage, WrappedErrors> {
+    let cover = lookup_song_cover(metadata, track).await;
+    or_placeholder(cover, &format!("the cover of {}", track))
+}
+
+async fn lookup_song_cover(
+    metadata: &MetadataChain,
+    track: &str,
+) -> Result<Option<DynamicImage>, WrappedErrors> {
+    let artist = track.split_once(" - ").map_or("", |(artist, _)| artist);
+    // the duration minutes listened worked out, when there is one, helps pick the right release
+    let key = track.to_string();
+    let duration = MetadataCache::open_async()
+        .await?
+        .run(move |c| c.get(CacheType::Duration, &key))
+        .await?
+        .and_then(|d| d.as_i64())
+        .filter(|d| *d > 0);
+    let track = TrackRef {
+        artist,
+        title: track_title(track),
+        mbid: None,
+        duration_ms: duration,
+    };
+    match metadata.album_art(track).await? {
+        Some(url) => Ok(Some(fetch_image(&url).await?)),
+        None => Ok(None),
+    }
+}
+
+async fn fetch_artist_icon(
+    metadata: &MetadataChain,
+    artist: &str,
+) -> Result<DynamicImage, WrappedErrors> {
+    let icon = lookup_artist_icon(metadata, artist).await;
+    or_placeholder(icon, &format!("an image of {}", artist))
+}
+
+async fn lookup_artist_icon(
+    metadata: &MetadataChain,
+    artist: &str,
+) -> Result<Option<DynamicImage>, WrappedErrors> {
+    let artist = ArtistRef {
+        name: artist,
+        recording_mbid: None,
+        track_title: None,
+    };
+    match metadata.artist_image(artist).await? {
+        Some(url) => Ok(Some(fetch_image(&url).await?)),
+        None => Ok(None),
+    }
+}
+
+// most played first and ties by name, an empty list means there is nothing to draw
+fn sort_plays(plays: &HashMap<String, i32>) -> Result<Vec<(&String, &i32)>, WrappedErrors> {
+    if plays.is_empty() {
+        return Err(no_scrobbles());
+    }
+    let mut sorted = plays.iter().collect::<Vec<_>>();
+    sorted.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    Ok(sorted)
+}
+
+fn no_scrobbles() -> WrappedErrors {
+    WrappedErrors::NotFound("no scrobbles in this range".to_string())
+}
+
+fn no_such_job() -> WrappedErrors {
+    WrappedErrors::NotFound("no such job".to_string())
+}
+
+// uploads can be whole streaming histories, hence the generous limit
+async fn read_body(data: Data<'_>) -> Result<String, WrappedErrors> {
+    let body = data
+        .open(256.mebibytes())
+        .into_string()
+        .await
+        .map_err(|e| WrappedErrors::InvalidRequest(e.to_string()))?;
+    if !body.is_complete() {
+        return Err(WrappedErrors::InvalidRequest(
+            "upload is larger than 256 MiB".to_string(),
+        ));
+    }
+    Ok(body.into_inner())
+}
+
 fn processable_response(processable: bool, error: &str) -> Value {
     json!({"processable": processable, "error": error})
 }
 
-#[get("/api/minuteslistened/<username>", format = "json")]
-pub async fn minutes_listened(username: &str) -> Value {
-    println!("{}", username);
-    let lfm_client = lfm::init_client(username);
-    let spotify_client = spotify::auth().await;
+#[derive(FromForm)]
+pub struct WrappedQuery {
+    year: Option<i32>,
+    from: Option<i64>,
+    to: Option<i64>,
+    tz: Option<String>,
+    source: Option<String>,
+    // varies which of each periods top genres make the genre evolution slides
+    seed: Option<u64>,
+    // months, quarters, seasons or from..to,from..to... see Periods::from_name
+    periods: Option<String>,
+    // how many of them get a genre evolution slide, spread evenly over the range.
+    // never more than daterange::MAX_PERIODS
+    period_count: Option<usize>,
+}
 
-    let total = calculate_year(lfm_client, &spotify_client).await;
-    let total_minutes = ((total.values().sum::<i64>()) / 1000) / 60;
-    let busiest = largest_value_hashmap(&total);
-    let busiest_time = (busiest[1] / 1000) / 60;
+fn query_source(username: &str, query: &WrappedQuery) -> Result<ListenSource, WrappedErrors> {
+    Ok(ListenSource::new(query.source.as_deref(), username)?)
+}
+
+// falls back to the users last.fm profile country when no tz is given, and to utc after that
+async fn query_range(username: &str, query: &WrappedQuery) -> Result<DateRange, WrappedErrors> {
+    let tz = match (&query.tz, query_source(username, query)?) {
+        (Some(name), _) => daterange::parse_timezone(name)?,
+        (None, ListenSource::LastFm(_)) => lfm::fetch_user_country(username)
+            .await
+            .and_then(|c| daterange::country_timezone(&c))
+            .unwrap_or(Tz::UTC),
+        (None, _) => Tz::UTC,
+    };
+    Ok(DateRange::from_query(query.year, query.from, query.to, tz)?)
+}
 
-    let img = imageprocessing::minutes_listened(total_minutes, busiest[0], busiest_time).unwrap();
-    img_mins_to_response(img, total_minutes)
+// three months spread over the range like the original slides, unless the query says otherwise
+fn query_periods(query: &WrappedQuery, range: DateRange) -> Result<Vec<Period>, WrappedErrors> {
+    let (periods, count) = match &query.periods {
+        Some(kind) => (Periods::from_name(kind, range.tz)?, query.period_count),
+        None => (Periods::Months, query.period_count.or(Some(3))),
+    };
+    let count = count.map_or(daterange::MAX_PERIODS, |c| c.min(daterange::MAX_PERIODS));
+    let periods = Periods::sample(periods.split(range), Some(count));
+    if periods.is_empty() {
+        return Err(WrappedErrors::InvalidRequest(
+            "no periods to show".to_string(),
+        ));
+    }
+    Ok(periods)
 }
 
-#[get("/api/topsong/<username>", format = "json")]
-pub async fn top_song(username: String) -> Value {
-    println!("{}", username);
-    let spotify_client = spotify::auth().await;
+async fn minutes_listened_image(
+    source: &ListenSource,
+    metadata: &MetadataChain,
+    range: DateRange,
+    progress: &Progress,
+) -> Result<(DynamicImage, i64), WrappedErrors> {
+    let total = calculate_year(source, metadata, range, progress).await?;
+    progress.phase("rendering images", 1);
+    let image = minutes_image(total, range)?;
+    progress.advance(1);
+    Ok(image)
+}
 
-    let top_tracks = lfm::fetch_top_5_tracks(&username).await;
-    let mut top_tracks_sorted = top_tracks.iter().collect::<Vec<_>>();
-    top_tracks_sorted.sort_by_key(|k| k.1);
-    top_tracks_sorted.reverse();
+async fn minutes_listened_result(
+    source: &ListenSource,
+    metadata: &MetadataChain,
+    range: DateRange,
+    progress: &Progress,
+) -> Result<Value, WrappedErrors> {
+    let (img, minutes) = minutes_listened_image(source, metadata, range, progress).await?;
+    Ok(img_mins_to_response(img, minutes))
+}
+
+async fn genre_evolution_images(
+    source: &ListenSource,
+    metadata: &MetadataChain,
+    periods: &[Period],
+    seed: Option<u64>,
+    progress: &Progress,
+) -> Result<Vec<DynamicImage>, WrappedErrors> {
+    let series = calculate_genre_periods(source, metadata, periods, progress).await?;
+    progress.phase("rendering images", periods.len() as u64);
+    imageprocessing::genre_evolution(series, seed, progress).map_err(render_error)
+}
+
+async fn genre_evolution_result(
+    source: &ListenSource,
+    metadata: &MetadataChain,
+    periods: &[Period],
+    seed: Option<u64>,
+    progress: &Progress,
+) -> Result<Value, WrappedErrors> {
+    let imgs = genre_evolution_images(source, metadata, periods, seed, progress).await?;
+    Ok(imgs_to_response(imgs))
+}
+
+// every slide plus the numbers behind them, the listens are only fetched once for all of it
+async fn wrapped_result(
+    source: &ListenSource,
+    metadata: &MetadataChain,
+    range: DateRange,
+    periods: &[Period],
+    seed: Option<u64>,
+    progress: &Progress,
+) -> Result<Value, WrappedErrors> {
+    let stats = calculate_stats(source, metadata, range, periods, progress).await?;
+    let busiest = match &stats.busiest_day {
+        Some(d) if !stats.top_tracks.is_empty() && !stats.top_artists.is_empty() => d,
+        _ => return Err(no_scrobbles()),
+    };
+
+    // minutes, top song, top 5, a genre slide per period and the final image
+    progress.phase("rendering images", 4 + periods.len() as u64);
+    let minutes_img = imageprocessing::minutes_listened(
+        stats.total_minutes,
+        busiest.timestamp,
+        busiest.minutes,
+        range.tz,
+    )
+    .map_err(render_error)?;
+    progress.advance(1);
+
+    let mut covers = Vec::with_capacity(stats.top_tracks.len());
+    for track in &stats.top_tracks {
+        let cover = fetch_song_cover(metadata, &track.name).await?;
+        covers.push((&track.name, (cover, &track.playcount)));
+    }
+    let top_song_img = imageprocessing::top_song(
+        stats.top_tracks[0].name.clone(),
+        stats.top_tracks[0].playcount as i64,
+        covers[0].1 .0.clone(),
+    )
+    .map_err(render_error)?;
+    progress.advance(1);
+    let top_5_img = imageprocessing::top_5_songs(covers).map_err(render_error)?;
+    progress.advance(1);
+
+    let genre_imgs = imageprocessing::genre_evolution(stats.series.clone(), seed, progress)
+        .map_err(render_error)?;
+
+    let icon_img = fetch_artist_icon(metadata, &stats.top_artists[0].name).await?;
+    let final_img = imageprocessing::final_image(
+        stats.total_minutes,
+        stats
+            .top_tracks
+            .iter()
+            .map(|t| track_title(&t.name))
+            .collect(),
+        stats.top_artists.iter().map(|a| a.name.as_str()).collect(),
+        icon_img,
+    )
+    .map_err(render_error)?;
+    progress.advance(1);
+
+    Ok(json!({
+        "stats": stats,
+        "images": {
+            "minuteslistened": img_to_b64(&minutes_img),
+            "topsong": img_to_b64(&top_song_img),
+            "top5songs": img_to_b64(&top_5_img),
+            "genreevolution": genre_imgs.iter().map(img_to_b64).collect::<Vec<_>>(),
+            "finalimage": img_to_b64(&final_img),
+        },
+    }))
+}
+
+#[get("/api/minuteslistened/<username>?<query..>")]
+pub async fn minutes_listened(
+    username: &str,
+    query: WrappedQuery,
+    shared_spotify: &State<SharedSpotify>,
+    encoding: ImageEncoding,
+) -> Result<ImageResponse, WrappedErrors> {
+    log::info!("minutes listened for {}", username);
+    let range = query_range(username, &query).await?;
+    let source = query_source(username, &query)?;
+    let metadata = metadata_chain(shared_spotify).await?;
+
+    let (img, minutes) =
+        minutes_listened_image(&source, &metadata, range, &Progress::none()).await?;
+    encoding.respond(img, |img| img_mins_to_response(img, minutes))
+}
+
+// same as minutes_listened but with playtime from a Streaming_History_Audio_*.json file
+#[post(
+    "/api/minuteslistened/<username>/spotifyhistory?<query..>",
+    format = "json",
+    data = "<history>"
+)]
+pub async fn minutes_listened_spotify_history(
+    username: &str,
+    query: WrappedQuery,
+    shared_spotify: &State<SharedSpotify>,
+    history: Data<'_>,
+) -> Result<Value, WrappedErrors> {
+    let range = query_range(username, &query).await?;
+    let history_text = read_body(history).await?;
+    let listens = spotifyhistory::parse_streaming_history(&history_text)?;
+    let metadata = metadata_chain(shared_spotify).await?;
+
+    let total = calculate_listens_year(&listens, &metadata, range, &Progress::none()).await?;
+    let (img, minutes) = minutes_image(total, range)?;
+    Ok(img_mins_to_response(img, minutes))
+}
+
+// stores an export so every endpoint can use it with ?source=import
+// format is csv (lastfm-to-csv), json (last.fm json export), spotify (extended streaming history)
+// or scrobblerlog (.scrobbler.log), tz is only needed for logs from devices without a time zone.
+// anyone could otherwise add listens to anyones wrapped, so this needs the admin token
+#[post("/api/import/<username>?<format>&<tz>", data = "<export>")]
+pub async fn import_listens(
+    _admin: AdminToken,
+    username: &str,
+    format: Option<&str>,
+    tz: Option<&str>,
+    export: Data<'_>,
+) -> Result<Value, WrappedErrors> {
+    let tz = match tz {
+        Some(name) => daterange::parse_timezone(name)?,
+        None => Tz::UTC,
+    };
+    let export_text = read_body(export).await?;
+    let parse_error = |e: Box<dyn Error>| WrappedErrors::Parse(e.to_string());
+    let listens = match format.unwrap_or("csv") {
+        "csv" => lfmexport::parse_csv(&export_text).map_err(parse_error)?,
+        "json" => lfmexport::parse_json(&export_text).map_err(parse_error)?,
+        "spotify" => spotifyhistory::parse_streaming_history(&export_text)?,
+        "scrobblerlog" => scrobblerlog::parse_scrobbler_log(&export_text, tz),
+        _ => {
+            return Err(WrappedErrors::InvalidRequest(
+                "unknown export format".to_string(),
+            ))
+        }
+    };
+    let imported = listens.len();
+    let stored = listenstore::save_listens(username, StoredSource::Import, listens).await?;
+    Ok(json!({ "imported": imported, "stored": stored }))
+}
+
+#[get("/api/topsong/<username>?<query..>")]
+pub async fn top_song(
+    username: String,
+    query: WrappedQuery,
+    shared_spotify: &State<SharedSpotify>,
+    encoding: ImageEncoding,
+) -> Result<ImageResponse, WrappedErrors> {
+    log::info!("top song for {}", username);
+    let range = query_range(&username, &query).await?;
+    let source = query_source(&username, &query)?;
+    let metadata = metadata_chain(shared_spotify).await?;
+
+    let top_tracks = source.top_5_tracks(range).await?;
+    let top_tracks_sorted = sort_plays(&top_tracks)?;
 
     let top_track = top_tracks_sorted[0].0;
-    let top_track_name = top_track.split(" - ").collect::<Vec<_>>()[1].to_string();
-
-    let song_cover_info =
-        spotify::find_song_cover(&spotify_client, top_track, &top_track_name).await;
-    let song_cover_url = song_cover_info["url"]
-        .as_str()
-        .unwrap_or("")
-        .trim_matches('\"');
-    let song_cover = reqwest::get(song_cover_url)
-        .await
-        .unwrap()
-        .bytes()
-        .await
-        .unwrap();
-    let song_cover_reader = ImageReader::new(Cursor::new(&song_cover))
-        .with_guessed_format()
-        .unwrap();
-    let song_cover_img = song_cover_reader.decode().unwrap();
+    let song_cover_img = fetch_song_cover(&metadata, top_track).await?;
 
     let img = imageprocessing::top_song(
         top_track.clone(),
         *top_tracks_sorted[0].1 as i64,
         song_cover_img,
     )
-    .unwrap();
-    img_to_response(img)
+    .map_err(render_error)?;
+    encoding.respond(img, img_to_response)
 }
 
-#[get("/api/top5songs/<username>", format = "json")]
-pub async fn top_5_songs(username: String) -> Value {
-    let spotify_client = spotify::auth().await;
+#[get("/api/top5songs/<username>?<query..>")]
+pub async fn top_5_songs(
+    username: String,
+    query: WrappedQuery,
+    shared_spotify: &State<SharedSpotify>,
+    encoding: ImageEncoding,
+) -> Result<ImageResponse, WrappedErrors> {
+    let range = query_range(&username, &query).await?;
+    let source = query_source(&username, &query)?;
+    let metadata = metadata_chain(shared_spotify).await?;
 
-    let top_tracks = lfm::fetch_top_5_tracks(&username).await;
-    let mut top_tracks_sorted = top_tracks.iter().collect::<Vec<_>>();
-    top_tracks_sorted.sort_by_key(|k| k.1);
-    top_tracks_sorted.reverse();
+    let top_tracks = source.top_5_tracks(range).await?;
     let mut meow = Vec::with_capacity(5);
-    for song in top_tracks_sorted {
-        let top_track_name = song.0.split(" - ").collect::<Vec<_>>()[1].to_string();
-        let song_cover_info =
-            spotify::find_song_cover(&spotify_client, song.0, &top_track_name).await;
-        let song_cover_url = song_cover_info["url"]
-            .as_str()
-            .unwrap_or("")
-            .trim_matches('\"');
-        let song_cover = reqwest::get(song_cover_url)
-            .await
-            .unwrap()
-            .bytes()
-            .await
-            .unwrap();
-        let song_cover_reader = ImageReader::new(Cursor::new(&song_cover))
-            .with_guessed_format()
-            .unwrap();
-        let song_cover_img = song_cover_reader.decode().unwrap();
+    for song in sort_plays(&top_tracks)? {
+        let song_cover_img = fetch_song_cover(&metadata, song.0).await?;
 
         meow.push((song.0, (song_cover_img, song.1)));
     }
 
-    let img = imageprocessing::top_5_songs(meow).unwrap();
-    img_to_response(img)
+    let img = imageprocessing::top_5_songs(meow).map_err(render_error)?;
+    encoding.respond(img, img_to_response)
 }
 
-#[get("/api/genreevolution/<username>", format = "json")]
-pub async fn genre_evolution(username: &str) -> Result<Value, Value> {
-    let lfm_client = lfm::init_client(username);
-    let spotify_client = spotify::auth().await;
+// everything the other endpoints return in one response, see wrapped_result
+#[get("/api/wrapped/<username>?<query..>", format = "json")]
+pub async fn wrapped(
+    username: &str,
+    query: WrappedQuery,
+    shared_spotify: &State<SharedSpotify>,
+) -> Result<Value, WrappedErrors> {
+    let range = query_range(username, &query).await?;
+    let source = query_source(username, &query)?;
+    let metadata = metadata_chain(shared_spotify).await?;
 
-    let months = match calculate_genre_months(lfm_client, spotify_client).await {
-        Ok(v) => v,
-        Err(e) => return Err(json!({"error": e.to_string()})),
-    };
-    //let meow = GenreMonths::new();
-    let imgs = imageprocessing::genre_evolution(months).unwrap();
-    Ok(imgs_to_response(imgs))
+    let periods = query_periods(&query, range)?;
+    wrapped_result(
+        &source,
+        &metadata,
+        range,
+        &periods,
+        query.seed,
+        &Progress::none(),
+    )
+    .await
+}
+
+// the numbers from /api/wrapped without rendering any images
+#[get("/api/stats/<username>?<query..>", format = "json")]
+pub async fn stats(
+    username: &str,
+    query: WrappedQuery,
+    shared_spotify: &State<SharedSpotify>,
+) -> Result<Json<Stats>, WrappedErrors> {
+    let range = query_range(username, &query).await?;
+    let source = query_source(username, &query)?;
+    let metadata = metadata_chain(shared_spotify).await?;
+
+    let periods = query_periods(&query, range)?;
+    let stats = calculate_stats(&source, &metadata, range, &periods, &Progress::none()).await?;
+    Ok(Json(stats))
+}
+
+// raw images only fit one slide per response, ?slide= picks which period (default 0)
+#[get("/api/genreevolution/<username>?<slide>&<query..>")]
+pub async fn genre_evolution(
+    username: &str,
+    slide: Option<usize>,
+    query: WrappedQuery,
+    shared_spotify: &State<SharedSpotify>,
+    encoding: ImageEncoding,
+) -> Result<ImageResponse, WrappedErrors> {
+    let range = query_range(username, &query).await?;
+    let source = query_source(username, &query)?;
+    let metadata = metadata_chain(shared_spotify).await?;
+    let periods = query_periods(&query, range)?;
+
+    let mut imgs =
+        genre_evolution_images(&source, &metadata, &periods, query.seed, &Progress::none()).await?;
+    if let ImageEncoding::Json = encoding {
+        return Ok(ImageResponse::Json(imgs_to_response(imgs)));
+    }
+    let slide = slide.unwrap_or(0);
+    if slide >= imgs.len() {
+        return Err(WrappedErrors::NotFound("no such slide".to_string()));
+    }
+    encoding.respond(imgs.swap_remove(slide), img_to_response)
 }
 
-#[get("/api/finalimage/<username>/<minutes>", format = "json")]
-pub async fn final_image(username: String, minutes: i64) -> Value {
-    println!("{}", username);
-    let spotify_client = spotify::auth().await;
+#[get("/api/finalimage/<username>/<minutes>?<query..>")]
+pub async fn final_image(
+    username: String,
+    minutes: i64,
+    query: WrappedQuery,
+    shared_spotify: &State<SharedSpotify>,
+    encoding: ImageEncoding,
+) -> Result<ImageResponse, WrappedErrors> {
+    log::info!("final image for {}", username);
+    let range = query_range(&username, &query).await?;
+    let source = query_source(&username, &query)?;
+    let metadata = metadata_chain(shared_spotify).await?;
 
-    let top_tracks = lfm::fetch_top_5_tracks(&username).await;
-    let mut top_tracks_sorted = top_tracks.iter().collect::<Vec<_>>();
-    top_tracks_sorted.sort_by_key(|k| k.1);
-    top_tracks_sorted.reverse();
-    let top_track_names = top_tracks_sorted
-        .iter()
-        .map(|(x, _)| x.split(" - ").collect_vec()[1])
+    let top_tracks = source.top_5_tracks(range).await?;
+    let top_track_names = sort_plays(&top_tracks)?
+        .into_iter()
+        .map(|(x, _)| track_title(x))
         .collect::<Vec<&str>>();
 
-    let top_artists = lfm::fetch_top_5_artists(&username).await;
-    let mut top_artists_sorted = top_artists.iter().collect::<Vec<_>>();
-    top_artists_sorted.sort_by_key(|k| k.1);
-    top_artists_sorted.reverse();
-    let top_artist_names = top_artists_sorted
-        .iter()
+    let top_artists = source.top_5_artists(range).await?;
+    let top_artist_names = sort_plays(&top_artists)?
+        .into_iter()
         .map(|x| x.0.as_str())
         .collect::<Vec<&str>>();
 
-    let icon_info = spotify::find_artist_icon(&spotify_client, top_artist_names[0]).await;
-    let icon_url = icon_info["url"].as_str().unwrap_or("").trim_matches('\"');
-    let icon = reqwest::get(icon_url).await.unwrap().bytes().await.unwrap();
-    let icon_reader = ImageReader::new(Cursor::new(&icon))
-        .with_guessed_format()
-        .unwrap();
-    let icon_img = icon_reader.decode().unwrap();
+    let icon_img = fetch_artist_icon(&metadata, top_artist_names[0]).await?;
+
+    let img = imageprocessing::final_image(minutes, top_track_names, top_artist_names, icon_img)
+        .map_err(render_error)?;
+    encoding.respond(img, img_to_response)
+}
+
+// same as the matching GET endpoints but runs in the background, poll /api/jobs/<id> for progress
+// kind is minuteslistened, genreevolution or wrapped
+#[post("/api/jobs/<kind>/<username>?<query..>")]
+pub async fn start_job(
+    kind: &str,
+    username: &str,
+    query: WrappedQuery,
+    jobs: &State<Jobs>,
+    shared_spotify: &State<SharedSpotify>,
+) -> Result<Value, WrappedErrors> {
+    if !matches!(kind, "minuteslistened" | "genreevolution" | "wrapped") {
+        return Err(WrappedErrors::InvalidRequest(
+            "unknown job kind".to_string(),
+        ));
+    }
+    let range = query_range(username, &query).await?;
+    let source = query_source(username, &query)?;
+    let metadata = metadata_chain(shared_spotify).await?;
+    let periods = query_periods(&query, range)?;
+    let seed = query.seed;
+    let progress = jobs.create(kind, username)?;
+    let id = progress.id().to_string();
 
-    let img =
-        imageprocessing::final_image(minutes, top_track_names, top_artist_names, icon_img).unwrap();
-    img_to_response(img)
+    let task_kind = kind.to_string();
+    let task_progress = progress.clone();
+    progress.spawn(async move {
+        let progress = task_progress;
+        match task_kind.as_str() {
+            "minuteslistened" => {
+                minutes_listened_result(&source, &metadata, range, &progress).await
+            }
+            "wrapped" => wrapped_result(&source, &metadata, range, &periods, seed, &progress).await,
+            _ => genre_evolution_result(&source, &metadata, &periods, seed, &progress).await,
+        }
+    });
+
+    Ok(json!({ "id": id }))
+}
+
+#[get("/api/jobs/<id>")]
+pub fn job_status(id: &str, jobs: &State<Jobs>) -> Result<Value, WrappedErrors> {
+    match jobs.status(id) {
+        Some(status) => Ok(json!(status)),
+        None => Err(no_such_job()),
+    }
+}
+
+// server-sent events with the job status every time it changes, ends once the job is done
+#[get("/api/jobs/<id>/events")]
+pub fn job_events(id: &str, jobs: &State<Jobs>) -> Result<EventStream![], WrappedErrors> {
+    let (status, rx) = match jobs.subscribe(id) {
+        Some(s) => s,
+        None => return Err(no_such_job()),
+    };
+    Ok(status_stream(status, rx))
+}
+
+// same as /api/jobs/<id>/events for the job most recently started for a user
+#[get("/api/progress/<username>")]
+pub fn user_progress(username: &str, jobs: &State<Jobs>) -> Result<EventStream![], WrappedErrors> {
+    let subscription = jobs.latest_for(username).and_then(|id| jobs.subscribe(&id));
+    let (status, rx) = match subscription {
+        Some(s) => s,
+        None => return Err(WrappedErrors::NotFound("no jobs for this user".to_string())),
+    };
+    Ok(status_stream(status, rx))
+}
+
+fn status_stream(status: JobStatus, mut rx: Receiver<JobStatus>) -> EventStream![] {
+    EventStream! {
+        let running = status.state == JobState::Running;
+        yield Event::json(&status);
+        if running {
+            loop {
+                match rx.recv().await {
+                    Ok(status) => {
+                        let running = status.state == JobState::Running;
+                        yield Event::json(&status);
+                        if !running {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        }
+    }
+}
+
+// the same response the GET endpoint would have given, once the job is done
+#[get("/api/jobs/<id>/result")]
+pub fn job_result(id: &str, jobs: &State<Jobs>) -> Result<Value, WrappedErrors> {
+    match jobs.result(id) {
+        Some(result) => Ok(result),
+        None => Err(WrappedErrors::NotFound(
+            "job not found or not done yet".to_string(),
+        )),
+    }
 }
 
 #[get("/api/isuserprocessable/<username>", format = "json")]
@@ -0,0 +1,393 @@
+use crate::error::WrappedErrors;
+use chrono::Utc;
+use dotenvy;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::{
+    env, fmt, fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, Once,
+    },
+    time::Duration,
+};
+use tokio::task;
+
+// lookups since the server started, not persisted so the cli cant report them
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub enum CacheErrors {
+    UnknownCacheType,
+}
+
+impl fmt::Display for CacheErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for CacheErrors {}
+
+#[derive(Clone, Copy)]
+pub enum CacheType {
+    Duration,
+    Genre,
+}
+
+impl CacheType {
+    pub const ALL: [CacheType; 2] = [CacheType::Duration, CacheType::Genre];
+
+    pub fn from_name(name: &str) -> Result<Self, CacheErrors> {
+        match name {
+            "duration" => Ok(CacheType::Duration),
+            "genre" => Ok(CacheType::Genre),
+            _ => Err(CacheErrors::UnknownCacheType),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CacheType::Duration => "duration",
+            CacheType::Genre => "genre",
+        }
+    }
+
+    // sql matching exactly the values is_negative calls negative
+    fn negative_sql(self) -> &'static str {
+        match self {
+            CacheType::Duration => {
+                "CASE WHEN json_valid(value) AND json_type(value) = 'integer'
+                THEN CAST(value AS INTEGER) <= 0 ELSE 1 END"
+            }
+            CacheType::Genre => {
+                "CASE WHEN json_valid(value) AND json_type(value) = 'array'
+                THEN NOT EXISTS (
+                    SELECT 1 FROM json_each(metadata_cache.value) g
+                    WHERE g.type = 'text' AND g.atom <> ''
+                )
+                ELSE 1 END"
+            }
+        }
+    }
+
+    // the json files the cache used to live in, imported once and then renamed
+    fn legacy_file(self) -> &'static str {
+        match self {
+            CacheType::Duration => "duration.json",
+            CacheType::Genre => "genre.json",
+        }
+    }
+
+    // lookups that found nothing are stored as 0 / [""], those get retried after a while.
+    // anything that isnt a usable duration or genre list counts as nothing found too
+    fn is_negative(self, value: &Value) -> bool {
+        match self {
+            CacheType::Duration => value.as_i64().is_none_or(|d| d <= 0),
+            CacheType::Genre => value
+                .as_array()
+                .is_none_or(|g| g.iter().all(|x| x.as_str().unwrap_or("").is_empty())),
+        }
+    }
+}
+
+fn cache_path() -> String {
+    let _ = dotenvy::dotenv();
+    env::var("CACHE_DB").unwrap_or("metadata.db".to_string())
+}
+
+// a week by default
+fn negative_ttl() -> i64 {
+    let _ = dotenvy::dotenv();
+    env::var("CACHE_NEGATIVE_TTL")
+        .ok()
+        .and_then(|t| t.parse::<i64>().ok())
+        .unwrap_or(7 * 24 * 60 * 60)
+}
+
+#[derive(Serialize)]
+pub struct CacheEntry {
+    pub key: String,
+    pub value: Value,
+    pub updated_at: i64,
+}
+
+// every entry is its own row, so concurrent requests only ever overwrite the entry they looked up
+#[derive(Clone)]
+pub struct MetadataCache {
+    // shared so a clone can be handed to the blocking pool, see run
+    conn: Arc<Mutex<Connection>>,
+    negative_ttl: i64,
+}
+
+impl MetadataCache {
+    pub fn open() -> rusqlite::Result<Self> {
+        let conn = Connection::open(cache_path())?;
+        conn.busy_timeout(Duration::from_secs(10))?;
+        let cache = Self::with_connection(conn)?;
+        // the cache is opened for every calculation, the old files only need looking for once
+        static LEGACY_IMPORTED: Once = Once::new();
+        LEGACY_IMPORTED.call_once(|| {
+            for ctype in CacheType::ALL {
+                if let Err(e) = cache.import_legacy(ctype) {
+                    log::warn!("couldnt import {}: {}", ctype.legacy_file(), e);
+                }
+            }
+        });
+        Ok(cache)
+    }
+
+    fn with_connection(conn: Connection) -> rusqlite::Result<Self> {
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS metadata_cache (
+                kind TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                updated_at INTEGER NOT NULL,
+                PRIMARY KEY (kind, key)
+            );",
+        )?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            negative_ttl: negative_ttl(),
+        })
+    }
+
+    // rusqlite blocks the thread it runs on, so async code opens and queries the cache on
+    // tokio's blocking pool instead of holding up a worker
+    pub async fn open_async() -> Result<Self, WrappedErrors> {
+        Ok(task::spawn_blocking(Self::open).await??)
+    }
+
+    pub async fn run<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&Self) -> rusqlite::Result<T> + Send + 'static,
+    ) -> Result<T, WrappedErrors> {
+        let cache = self.clone();
+        Ok(task::spawn_blocking(move || query(&cache)).await??)
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    fn import_legacy(&self, ctype: CacheType) -> rusqlite::Result<()> {
+        let path = Path::new(ctype.legacy_file());
+        let legacy: Value = match fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_default(),
+            Err(_) => return Ok(()),
+        };
+        if let Some(entries) = legacy.as_object() {
+            for (key, value) in entries {
+                if !key.is_empty() {
+                    self.set(ctype, key, value)?;
+                }
+            }
+        }
+        let _ = fs::rename(path, format!("{}.migrated", ctype.legacy_file()));
+        Ok(())
+    }
+
+    // None when there is no entry or the entry is a negative result that expired
+    pub fn get(&self, ctype: CacheType, key: &str) -> rusqlite::Result<Option<Value>> {
+        let entry = self
+            .conn()
+            .query_row(
+                "SELECT value, updated_at FROM metadata_cache WHERE kind = ?1 AND key = ?2",
+                params![ctype.as_str(), key],
+                |r| Ok((r.get::<_, String>(0)?, r.get::<_, i64>(1)?)),
+            )
+            .optional()?;
+        let value = entry.and_then(|(value, updated_at)| {
+            let value: Value = serde_json::from_str(&value).ok()?;
+            let expired = Utc::now().timestamp() - updated_at > self.negative_ttl;
+            if expired && ctype.is_negative(&value) {
+                None
+            } else {
+                Some(value)
+            }
+        });
+        match value {
+            Some(_) => HITS.fetch_add(1, Ordering::Relaxed),
+            None => MISSES.fetch_add(1, Ordering::Relaxed),
+        };
+        Ok(value)
+    }
+
+    // the entry as stored, expired or not and without counting towards the hit rate
+    pub fn lookup(&self, ctype: CacheType, key: &str) -> rusqlite::Result<Option<CacheEntry>> {
+        self.conn()
+            .query_row(
+                "SELECT key, value, updated_at FROM metadata_cache WHERE kind = ?1 AND key = ?2",
+                params![ctype.as_str(), key],
+                row_to_entry,
+            )
+            .optional()
+    }
+
+    // search is a case insensitive substring of the key ("Artist - Title" or the artist name),
+    // % and _ in it are matched literally
+    pub fn list(
+        &self,
+        ctype: CacheType,
+        search: &str,
+        limit: i64,
+        offset: i64,
+    ) -> rusqlite::Result<Vec<CacheEntry>> {
+        let conn = self.conn();
+        let mut select = conn.prepare(
+            "SELECT key, value, updated_at FROM metadata_cache
+            WHERE kind = ?1 AND key LIKE '%' || ?2 || '%' ESCAPE '\\'
+            ORDER BY key LIMIT ?3 OFFSET ?4",
+        )?;
+        let search = search
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        let entries = select
+            .query_map(params![ctype.as_str(), search, limit, offset], row_to_entry)?
+            .collect::<rusqlite::Result<Vec<CacheEntry>>>()?;
+        Ok(entries)
+    }
+
+    // true if there was an entry to delete
+    pub fn delete(&self, ctype: CacheType, key: &str) -> rusqlite::Result<bool> {
+        let deleted = self.conn().execute(
+            "DELETE FROM metadata_cache WHERE kind = ?1 AND key = ?2",
+            params![ctype.as_str(), key],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    // every entry as one object, same shape the old duration.json/genre.json files had
+    pub fn export(&self, ctype: CacheType) -> rusqlite::Result<Value> {
+        let mut export = Map::new();
+        for e in self.list(ctype, "", -1, 0)? {
+            export.insert(e.key, e.value);
+        }
+        Ok(Value::Object(export))
+    }
+
+    // entries and negative entries per cache
+    pub fn stats(&self) -> rusqlite::Result<Value> {
+        let mut kinds = Map::new();
+        for ctype in CacheType::ALL {
+            let entries: i64 = self.conn().query_row(
+                "SELECT COUNT(*) FROM metadata_cache WHERE kind = ?1",
+                params![ctype.as_str()],
+                |r| r.get(0),
+            )?;
+            let negative: i64 = self.conn().query_row(
+                &format!(
+                    "SELECT COUNT(*) FROM metadata_cache WHERE kind = ?1 AND {}",
+                    ctype.negative_sql()
+                ),
+                params![ctype.as_str()],
+                |r| r.get(0),
+            )?;
+            kinds.insert(
+                ctype.as_str().to_string(),
+                json!({ "entries": entries, "negative": negative }),
+            );
+        }
+        Ok(Value::Object(kinds))
+    }
+
+    // hits and misses of this process
+    pub fn lookups() -> Value {
+        let hits = HITS.load(Ordering::Relaxed);
+        let misses = MISSES.load(Ordering::Relaxed);
+        let hit_rate = if hits + misses == 0 {
+            0.0
+        } else {
+            hits as f64 / (hits + misses) as f64
+        };
+        json!({
+            "hits": hits,
+            "misses": misses,
+            "hit_rate": hit_rate,
+        })
+    }
+
+    pub fn set(&self, ctype: CacheType, key: &str, value: &Value) -> rusqlite::Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO metadata_cache (kind, key, value, updated_at)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![
+                ctype.as_str(),
+                key,
+                value.to_string(),
+                Utc::now().timestamp()
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+fn row_to_entry(r: &rusqlite::Row) -> rusqlite::Result<CacheEntry> {
+    let value: String = r.get(1)?;
+    Ok(CacheEntry {
+        key: r.get(0)?,
+        value: serde_json::from_str(&value).unwrap_or(Value::Null),
+        updated_at: r.get(2)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory_cache() -> MetadataCache {
+        MetadataCache::with_connection(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn stats_count_the_same_negatives_lookups_retry() {
+        let cases = [
+            (CacheType::Duration, json!(0)),
+            (CacheType::Duration, json!(-1)),
+            (CacheType::Duration, json!(null)),
+            (CacheType::Duration, json!("180000")),
+            (CacheType::Duration, json!(1.5)),
+            (CacheType::Duration, json!(180000)),
+            (CacheType::Genre, json!([])),
+            (CacheType::Genre, json!([""])),
+            (CacheType::Genre, json!(["", ""])),
+            (CacheType::Genre, json!([null])),
+            (CacheType::Genre, json!(null)),
+            (CacheType::Genre, json!("rock")),
+            (CacheType::Genre, json!(["rock"])),
+            (CacheType::Genre, json!(["", "indie pop"])),
+        ];
+        for (ctype, value) in cases {
+            let cache = memory_cache();
+            cache.set(ctype, "key", &value).unwrap();
+            let stats = cache.stats().unwrap();
+            let counted = stats[ctype.as_str()]["negative"].as_i64().unwrap() == 1;
+            assert_eq!(counted, ctype.is_negative(&value), "{}", value);
+        }
+    }
+
+    #[test]
+    fn list_matches_wildcards_literally() {
+        let cache = memory_cache();
+        for key in ["100% - Song", "1000 - Song", "a_b - Song", "axb - Song"] {
+            cache.set(CacheType::Duration, key, &json!(1)).unwrap();
+        }
+        let keys = |search| {
+            cache
+                .list(CacheType::Duration, search, -1, 0)
+                .unwrap()
+                .into_iter()
+                .map(|e| e.key)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys("100%"), vec!["100% - Song"]);
+        assert_eq!(keys("a_b"), vec!["a_b - Song"]);
+        assert_eq!(keys("song").len(), 4);
+    }
+}
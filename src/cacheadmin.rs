@@ -0,0 +1,120 @@
+use crate::{
+    cache::{CacheType, MetadataCache},
+    error::WrappedErrors,
+};
+use dotenvy;
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome, Request},
+    serde::json::Json,
+};
+use serde_json::{json, Value};
+use std::env;
+
+// cache admin and imports are off unless ADMIN_TOKEN is set, requests need
+// "Authorization: Bearer <token>"
+pub struct AdminToken;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminToken {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let _ = dotenvy::dotenv();
+        let token = match env::var("ADMIN_TOKEN") {
+            Ok(t) if !t.is_empty() => t,
+            _ => return Outcome::Error((Status::Forbidden, ())),
+        };
+        match req.headers().get_one("Authorization") {
+            Some(h) if h == format!("Bearer {}", token) => Outcome::Success(AdminToken),
+            _ => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+async fn open_cache(kind: &str) -> Result<(MetadataCache, CacheType), WrappedErrors> {
+    let ctype = CacheType::from_name(kind)?;
+    let cache = MetadataCache::open_async().await?;
+    Ok((cache, ctype))
+}
+
+fn no_such_entry() -> WrappedErrors {
+    WrappedErrors::NotFound("no such entry".to_string())
+}
+
+#[get("/api/cache/<kind>?<search>&<limit>&<offset>")]
+pub async fn list_entries(
+    _admin: AdminToken,
+    kind: &str,
+    search: Option<&str>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+) -> Result<Value, WrappedErrors> {
+    let (cache, ctype) = open_cache(kind).await?;
+    let search = search.unwrap_or("").to_string();
+    let entries = cache
+        .run(move |c| c.list(ctype, &search, limit.unwrap_or(100), offset.unwrap_or(0)))
+        .await?;
+    Ok(json!({ "entries": entries }))
+}
+
+#[get("/api/cache/<kind>/entry?<key>")]
+pub async fn lookup_entry(
+    _admin: AdminToken,
+    kind: &str,
+    key: &str,
+) -> Result<Value, WrappedErrors> {
+    let (cache, ctype) = open_cache(kind).await?;
+    let key = key.to_string();
+    match cache.run(move |c| c.lookup(ctype, &key)).await? {
+        Some(entry) => Ok(json!(entry)),
+        None => Err(no_such_entry()),
+    }
+}
+
+// for fixing what a bad spotify match put in, e.g. a duration in ms or a list of genres
+#[put("/api/cache/<kind>/entry?<key>", format = "json", data = "<value>")]
+pub async fn override_entry(
+    _admin: AdminToken,
+    kind: &str,
+    key: &str,
+    value: Json<Value>,
+) -> Result<Value, WrappedErrors> {
+    let (cache, ctype) = open_cache(kind).await?;
+    let key = key.to_string();
+    let entry = cache
+        .run(move |c| {
+            c.set(ctype, &key, &value.into_inner())?;
+            c.lookup(ctype, &key)
+        })
+        .await?;
+    Ok(json!(entry))
+}
+
+#[delete("/api/cache/<kind>/entry?<key>")]
+pub async fn delete_entry(
+    _admin: AdminToken,
+    kind: &str,
+    key: &str,
+) -> Result<Value, WrappedErrors> {
+    let (cache, ctype) = open_cache(kind).await?;
+    let key = key.to_string();
+    if !cache.run(move |c| c.delete(ctype, &key)).await? {
+        return Err(no_such_entry());
+    }
+    Ok(json!({ "deleted": true }))
+}
+
+#[get("/api/cache/<kind>/export")]
+pub async fn export_entries(_admin: AdminToken, kind: &str) -> Result<Value, WrappedErrors> {
+    let (cache, ctype) = open_cache(kind).await?;
+    cache.run(move |c| c.export(ctype)).await
+}
+
+#[get("/api/cachestats")]
+pub async fn cache_stats(_admin: AdminToken) -> Result<Value, WrappedErrors> {
+    let cache = MetadataCache::open_async().await?;
+    let mut stats = MetadataCache::lookups();
+    stats["caches"] = cache.run(|c| c.stats()).await?;
+    Ok(stats)
+}
@@ -1,84 +1,47 @@
-use crate::{lfm, spotify};
-use chrono::{Datelike, Local, Months, TimeZone, Utc};
-use futures_util::pin_mut;
-use futures_util::stream::StreamExt;
-use lastfm::Client;
-use rspotify::ClientCredsSpotify;
+use crate::{
+    cache::{CacheType, MetadataCache},
+    daterange::{self, DateRange, Period},
+    jobs::Progress,
+    listens::Listen,
+    metadata::{ArtistRef, MetadataChain},
+    source::ListenSource,
+    taxonomy::{GenreLevel, Taxonomy},
+};
+use chrono::{Datelike, Days, TimeZone, Timelike};
+use chrono_tz::Tz;
+use itertools::Itertools;
+use serde::Serialize;
 use serde_json::{json, Value};
-use std::{collections::HashMap, error::Error, fs};
+use std::{collections::HashMap, error::Error};
 
-enum CacheType {
-    Duration,
-    Genre,
+#[derive(Clone, Serialize)]
+pub struct GenreShare {
+    pub genre: String,
+    // scrobbles of every artist carrying the genre
+    pub scrobbles: i64,
+    // the same as a fraction of all scrobbles in the period, an artist with two genres counts
+    // for both so these dont add up to 1
+    pub share: f64,
 }
 
-#[derive(Clone)]
-pub struct GenreMonths {
-    pub january: HashMap<String, Vec<Value>>,
-    pub april: HashMap<String, Vec<Value>>,
-    pub august: HashMap<String, Vec<Value>>,
+#[derive(Clone, Default)]
+pub struct PeriodGenres {
+    // the most scrobbled artists with their canonical genres, most scrobbled first
+    pub artists: Vec<(String, Vec<String>)>,
+    // every canonical genre in the period, most scrobbled first
+    pub genres: Vec<GenreShare>,
 }
 
-impl GenreMonths {
-    pub fn new() -> Self {
-        Self {
-            january: HashMap::new(),
-            april: HashMap::new(),
-            august: HashMap::new(),
-        }
-    }
-    pub fn set(&mut self, i: i8, v: HashMap<String, Vec<Value>>) {
-        match i {
-            0 => self.january = v,
-            1 => self.april = v,
-            2 => self.august = v,
-            default => panic!(
-                "outta bounds!!!!!!!!! index {} is HIGHER than 2!! >:c",
-                default
-            ),
-        }
-    }
-    pub fn get(self, i: usize) -> HashMap<String, Vec<Value>> {
-        match i {
-            0 => self.january,
-            1 => self.april,
-            2 => self.august,
-            default => panic!(
-                "outta bounds!!!!!!!!! index {} is HIGHER than 2!! >:c",
-                default
-            ),
-        }
-    }
-    pub fn get_month_string(self, i: usize) -> String {
-        vec!["January", "April", "August"][i].to_string()
-    }
+#[derive(Clone)]
+pub struct GenrePeriod {
+    pub period: Period,
+    pub genres: PeriodGenres,
 }
 
-impl Default for GenreMonths {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
-fn load_cache(ctype: CacheType) -> Value {
-    let cache_text = match ctype {
-        CacheType::Duration => fs::read_to_string("duration.json").unwrap_or("{}".to_string()),
-        CacheType::Genre => fs::read_to_string("genre.json").unwrap_or("{}".to_string()),
-    };
-    let cache: Value = serde_json::from_str(&cache_text).unwrap();
-    cache
-}
-
-fn save_cache(cache: Value, ctype: CacheType) {
-    let cache_text = serde_json::to_string_pretty(&cache).unwrap();
-    match ctype {
-        CacheType::Duration => {
-            let _ = fs::write("duration.json", cache_text);
-        }
-        CacheType::Genre => {
-            let _ = fs::write("genre.json", cache_text);
-        }
-    }
+// what the genre evolution slides are drawn from, one period per slide in order
+#[derive(Clone, Default)]
+pub struct GenreSeries {
+    pub periods: Vec<GenrePeriod>,
 }
 
 pub fn largest_value_hashmap(hm: &HashMap<i64, i64>) -> Vec<i64> {
@@ -121,148 +84,335 @@ pub fn sort_value(v: Value) -> Vec<(String, String)> {
     vvec
 }
 
+#[derive(Clone, Copy)]
+pub enum Granularity {
+    Hour,
+    Day,
+    Week,
+}
+
+impl Granularity {
+    pub fn seconds(self) -> i64 {
+        match self {
+            Granularity::Hour => 60 * 60,
+            Granularity::Day => 24 * 60 * 60,
+            Granularity::Week => 7 * 24 * 60 * 60,
+        }
+    }
+
+    // start of the bucket `ts` falls into, using local midnight in `tz` for days and weeks
+    pub fn bucket(self, tz: Tz, ts: i64) -> i64 {
+        let local = tz.timestamp_opt(ts, 0).unwrap();
+        let date = match self {
+            Granularity::Hour => return ts - (local.minute() * 60 + local.second()) as i64,
+            Granularity::Day => local.date_naive(),
+            Granularity::Week => {
+                local.date_naive() - Days::new(local.weekday().num_days_from_monday() as u64)
+            }
+        };
+        daterange::start_of_day(tz, date).timestamp()
+    }
+}
+
+// fetches every listen in the range once and sums durations per bucket
 async fn calculate_scrobble_time(
-    lfm_client: Client<String, &str>,
-    spotify_client: ClientCredsSpotify,
-    from: i64,
-    to: i64,
-) -> Result<i32, Box<dyn Error>> {
-    let track_stream = lfm_client
-        .recent_tracks(Some(from), Some(to))
-        .await?
-        .into_stream();
-    let mut total_seconds: i32 = 0;
-    let mut cached_durs = load_cache(CacheType::Duration);
-    // add item for errored out tracks
-    cached_durs[""] = json!(0);
-    pin_mut!(track_stream);
-    while let Some(i) = track_stream.next().await {
-        match i {
-            Ok(t) => {
-                let track_name = format!("{} - {}", t.artist.name, t.name);
-                let mut dur;
-                if cached_durs[&track_name] == Value::Null {
-                    let info = lfm::get_track_info(&t.artist.name, &t.name).await;
-                    dur = lfm::get_track_duration(&info);
-                    if dur == 0 {
-                        dur = spotify::find_song_duration(&spotify_client, &track_name, &t.name)
-                            .await
-                            .unwrap_or(0) as i32;
-                    }
-                    cached_durs[&track_name] = json!(dur);
-                } else {
-                    dur = cached_durs[&track_name].as_i64().unwrap() as i32;
+    source: &ListenSource,
+    metadata: &MetadataChain,
+    range: DateRange,
+    granularity: Granularity,
+    progress: &Progress,
+) -> Result<HashMap<i64, i64>, Box<dyn Error>> {
+    progress.phase("fetching scrobbles", 0);
+    let listens = source.listens(range).await?;
+    progress.phase(&format!("fetched {} scrobbles", listens.len()), 0);
+    calculate_listen_time(&listens, metadata, range, granularity, progress).await
+}
+
+// listens that dont know how long they were played for fall back to the track duration
+async fn calculate_listen_time(
+    listens: &[Listen],
+    metadata: &MetadataChain,
+    range: DateRange,
+    granularity: Granularity,
+    progress: &Progress,
+) -> Result<HashMap<i64, i64>, Box<dyn Error>> {
+    let mut buckets: HashMap<i64, i64> = HashMap::new();
+    let cache = MetadataCache::open_async().await?;
+    progress.phase("resolving durations", listens.len() as u64);
+    for t in listens {
+        progress.advance(1);
+        if t.timestamp < range.from || t.timestamp >= range.to {
+            continue;
+        }
+        let dur = match t.played_ms {
+            Some(ms) => ms,
+            None => {
+                let track_name = t.track_name();
+                let key = track_name.clone();
+                let cached = cache.run(move |c| c.get(CacheType::Duration, &key)).await?;
+                match cached {
+                    Some(dur) => dur.as_i64().unwrap_or(0),
+                    // a failed lookup counts as unknown for this run and is asked again next time
+                    None => match metadata.duration(t.track_ref()).await {
+                        Ok(dur) => {
+                            let dur = dur.unwrap_or(0);
+                            let key = track_name.clone();
+                            cache
+                                .run(move |c| c.set(CacheType::Duration, &key, &json!(dur)))
+                                .await?;
+                            dur
+                        }
+                        Err(e) => {
+                            log::warn!("couldnt look up the duration of {}: {}", track_name, e);
+                            0
+                        }
+                    },
                 }
-                total_seconds += dur;
-            }
-            Err(_e) => {
-                //println!("error: {:?}", e);
             }
+        };
+        *buckets
+            .entry(granularity.bucket(range.tz, t.timestamp))
+            .or_insert(0) += dur;
+    }
+    // a bucket cant hold more listening time than its own length
+    let max_ms = granularity.seconds() * 1000;
+    for time in buckets.values_mut() {
+        if *time > max_ms {
+            *time = 0;
+        }
+    }
+    Ok(buckets)
+}
+
+// top 5 by play count, plays under 30 seconds dont count just like a scrobble wouldnt.
+// ties go by name so the same listens always give the same top 5
+fn top_5_listened(listens: &[Listen], key: impl Fn(&Listen) -> String) -> HashMap<String, i32> {
+    let mut plays: HashMap<String, i32> = HashMap::new();
+    for t in listens {
+        if matches!(t.played_ms, Some(ms) if ms < 30 * 1000) {
+            continue;
         }
+        *plays.entry(key(t)).or_insert(0) += 1;
     }
-    //let total_minutes: i32 = (total_seconds / 1000) / 60;
-    save_cache(cached_durs, CacheType::Duration);
-    Ok(total_seconds)
+    plays
+        .into_iter()
+        .sorted_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)))
+        .take(5)
+        .collect()
+}
+
+pub fn top_5_listened_tracks(listens: &[Listen]) -> HashMap<String, i32> {
+    top_5_listened(listens, |t| t.track_name())
 }
 
+pub fn top_5_listened_artists(listens: &[Listen]) -> HashMap<String, i32> {
+    top_5_listened(listens, |t| t.artist.clone())
+}
+
+// genres are canonicalized with the taxonomy and weighted by how much each artist was played
 async fn calculate_top_genres(
-    lfm_client: Client<String, &str>,
-    spotify_client: ClientCredsSpotify,
-    from: i64,
-    to: i64,
-) -> Result<HashMap<String, Vec<Value>>, Box<dyn Error>> {
-    let track_stream = lfm_client
-        .recent_tracks(Some(from), Some(to))
-        .await?
-        .into_stream();
-    let mut cached_genres = load_cache(CacheType::Genre);
-    let mut artist_scrobbles: Value = Default::default();
-    // add item for errored out tracks
-    cached_genres[""] = json!(0);
-    pin_mut!(track_stream);
-    while let Some(i) = track_stream.next().await {
-        match i {
-            Ok(t) => {
-                if cached_genres[&t.artist.name] == Value::Null {
-                    let artist = &t.artist.name.split(&[';', ',']).collect::<Vec<&str>>()[0];
-                    let genres = spotify::find_artist_genres(&spotify_client, artist).await;
-                    cached_genres[&t.artist.name] = genres;
+    listens: &[Listen],
+    metadata: &MetadataChain,
+    range: DateRange,
+    progress: &Progress,
+) -> Result<PeriodGenres, Box<dyn Error>> {
+    progress.phase("resolving genres", listens.len() as u64);
+    let cache = MetadataCache::open_async().await?;
+    let taxonomy = Taxonomy::get();
+    let level = GenreLevel::from_env();
+    let mut artist_genres: HashMap<String, Vec<String>> = HashMap::new();
+    let mut artist_scrobbles: HashMap<String, i64> = HashMap::new();
+    for t in listens {
+        progress.advance(1);
+        if t.timestamp < range.from || t.timestamp >= range.to {
+            continue;
+        }
+        if !artist_genres.contains_key(&t.artist) {
+            let key = t.artist.clone();
+            let cached = cache.run(move |c| c.get(CacheType::Genre, &key)).await?;
+            let genres = match cached {
+                Some(genres) => genres,
+                None => {
+                    let artist = ArtistRef {
+                        name: t.artist.split(&[';', ',']).next().unwrap_or(&t.artist),
+                        recording_mbid: t.mbid.as_deref(),
+                        track_title: Some(&t.title),
+                    };
+                    // like durations a failed lookup means no genres for this run only, and when
+                    // one provider failed what the others found is used without being cached
+                    match metadata.genres(artist).await {
+                        Ok(lookup) => {
+                            let genres = if lookup.genres.is_empty() {
+                                json!([""])
+                            } else {
+                                json!(lookup.genres)
+                            };
+                            if lookup.complete {
+                                let (key, value) = (t.artist.clone(), genres.clone());
+                                cache
+                                    .run(move |c| c.set(CacheType::Genre, &key, &value))
+                                    .await?;
+                            }
+                            genres
+                        }
+                        Err(e) => {
+                            log::warn!("couldnt look up the genres of {}: {}", t.artist, e);
+                            json!([])
+                        }
+                    }
                 }
-                artist_scrobbles[&t.artist.name] =
-                    json!(artist_scrobbles[&t.artist.name].as_i64().unwrap_or(0) + 1);
-            }
-            Err(_e) => {
-                //println!("error: {:?}", e);
-            }
+            };
+            let raw = genres
+                .as_array()
+                .cloned()
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|g| g.as_str().map(String::from))
+                .collect::<Vec<_>>();
+            artist_genres.insert(t.artist.clone(), taxonomy.canonicalize_all(&raw, level));
         }
+        *artist_scrobbles.entry(t.artist.clone()).or_insert(0) += 1;
     }
 
-    let mut top_by_scrobble = sort_value(artist_scrobbles);
-    let mut top_genres: HashMap<String, Vec<Value>> = HashMap::with_capacity(5);
-    top_by_scrobble.drain(3..top_by_scrobble.len());
-    for (i, _) in top_by_scrobble {
-        top_genres.insert(i.clone(), cached_genres[i].as_array().unwrap().clone());
+    let total = artist_scrobbles.values().sum::<i64>();
+    let mut genre_scrobbles: HashMap<&String, i64> = HashMap::new();
+    for (artist, scrobbles) in &artist_scrobbles {
+        for genre in &artist_genres[artist] {
+            *genre_scrobbles.entry(genre).or_insert(0) += scrobbles;
+        }
     }
+    let mut genres = genre_scrobbles
+        .into_iter()
+        .map(|(genre, scrobbles)| GenreShare {
+            genre: genre.clone(),
+            scrobbles,
+            share: scrobbles as f64 / total as f64,
+        })
+        .collect::<Vec<_>>();
+    genres.sort_by(|a, b| b.scrobbles.cmp(&a.scrobbles).then(a.genre.cmp(&b.genre)));
+
+    let mut top_by_scrobble = artist_scrobbles.into_iter().collect::<Vec<_>>();
+    top_by_scrobble.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    top_by_scrobble.truncate(3);
+    let artists = top_by_scrobble
+        .into_iter()
+        .map(|(artist, _)| {
+            let genres = artist_genres.remove(&artist).unwrap_or_default();
+            (artist, genres)
+        })
+        .collect();
 
-    save_cache(cached_genres, CacheType::Genre);
-    Ok(top_genres)
+    Ok(PeriodGenres { artists, genres })
 }
 
 pub async fn calculate_year(
-    lfm_client: Client<String, &str>,
-    spotify_client: &ClientCredsSpotify,
-) -> HashMap<i64, i64> {
-    let now = Local::now();
-    //let yearago = now.checked_sub_months(Months::new(12)).unwrap();
-    let yearago = Utc
-        .with_ymd_and_hms(now.year(), 1, 1, 0, 0, 0)
-        .unwrap()
-        .timestamp();
+    source: &ListenSource,
+    metadata: &MetadataChain,
+    range: DateRange,
+    progress: &Progress,
+) -> Result<HashMap<i64, i64>, Box<dyn Error>> {
+    calculate_scrobble_time(source, metadata, range, Granularity::Day, progress).await
+}
+
+// same as calculate_year but for listens that were imported instead of scrobbled
+pub async fn calculate_listens_year(
+    listens: &[Listen],
+    metadata: &MetadataChain,
+    range: DateRange,
+    progress: &Progress,
+) -> Result<HashMap<i64, i64>, Box<dyn Error>> {
+    calculate_listen_time(listens, metadata, range, Granularity::Day, progress).await
+}
 
-    // subtracting one since it would count 365 to 366 (or december 31st to january 1st) otherwise
-    let days_in_year = if now.year() % 4 == 0 { 365 } else { 364 };
-    let seconds_in_day = 24 * 60 * 60;
-    let mut days: HashMap<i64, i64> = HashMap::with_capacity(days_in_year as usize);
-    for i in 1..days_in_year {
-        let from_ts = yearago + (i * seconds_in_day);
-        let to_ts = yearago + ((i + 1) * seconds_in_day);
-        let time =
-            calculate_scrobble_time(lfm_client.clone(), spotify_client.clone(), from_ts, to_ts)
-                .await
-                .unwrap_or(0) as i64;
-        days.insert(from_ts, if time > 24 * 60 * 60 * 1000 { 0 } else { time });
-        //println!("{}", i);
+pub async fn calculate_genre_periods(
+    source: &ListenSource,
+    metadata: &MetadataChain,
+    periods: &[Period],
+    progress: &Progress,
+) -> Result<GenreSeries, Box<dyn Error>> {
+    // one fetch covering every period, like calculate_stats
+    let range = match periods.first() {
+        Some(first) => DateRange {
+            from: periods
+                .iter()
+                .map(|p| p.range.from)
+                .min()
+                .unwrap_or(first.range.from),
+            to: periods
+                .iter()
+                .map(|p| p.range.to)
+                .max()
+                .unwrap_or(first.range.to),
+            tz: first.range.tz,
+        },
+        None => return Ok(GenreSeries::default()),
+    };
+    progress.phase("fetching scrobbles", 0);
+    let listens = source.listens(range).await?;
+    progress.phase(&format!("fetched {} scrobbles", listens.len()), 0);
+    calculate_listens_genre_periods(&listens, metadata, periods, progress).await
+}
+
+// same as calculate_genre_periods but picks the periods out of listens that were already fetched
+pub async fn calculate_listens_genre_periods(
+    listens: &[Listen],
+    metadata: &MetadataChain,
+    periods: &[Period],
+    progress: &Progress,
+) -> Result<GenreSeries, Box<dyn Error>> {
+    let mut series = GenreSeries::default();
+    for period in periods {
+        series.periods.push(GenrePeriod {
+            period: period.clone(),
+            genres: calculate_top_genres(listens, metadata, period.range, progress).await?,
+        });
     }
-    days
+
+    Ok(series)
 }
 
-// Vec<Vec<(String, Vec<Value>)>>
-pub async fn calculate_genre_months(
-    lfm_client: Client<String, &str>,
-    spotify_client: ClientCredsSpotify,
-) -> Result<GenreMonths, Box<dyn Error>> {
-    let now = Local::now().naive_utc();
-    let yearago = now.checked_sub_months(Months::new(12)).unwrap();
-    // january, april, august
-    let mut months = GenreMonths::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    for i in 0..3 {
-        let from_ts = yearago
-            .checked_add_months(Months::new(i * 3))
-            .unwrap()
-            .and_utc()
-            .timestamp();
-        let to_ts = yearago
-            .checked_add_months(Months::new((i * 3) + 1))
-            .unwrap()
-            .and_utc()
-            .timestamp();
-        months.set(
-            i as i8,
-            calculate_top_genres(lfm_client.clone(), spotify_client.clone(), from_ts, to_ts)
-                .await?,
-        );
+    #[test]
+    fn days_follow_the_listeners_time_zone() {
+        // 2024-01-02 03:00 utc is still the evening of january 1st in los angeles
+        let tz = daterange::country_timezone("United States").unwrap();
+        assert_eq!(tz, Tz::America__New_York);
+        let la = Tz::America__Los_Angeles;
+        let listen = 1_704_164_400;
+        assert_eq!(Granularity::Day.bucket(la, listen), 1_704_096_000);
+        assert_eq!(Granularity::Day.bucket(Tz::UTC, listen), 1_704_153_600);
+        assert_eq!(daterange::country_timezone("Atlantis"), None);
     }
 
-    Ok(months)
+    #[test]
+    fn top_5_ties_are_broken_by_name() {
+        let listens = ["f", "e", "d", "c", "b", "a", "a"]
+            .iter()
+            .enumerate()
+            .map(|(i, artist)| Listen {
+                artist: artist.to_string(),
+                title: "Song".to_string(),
+                timestamp: i as i64,
+                played_ms: None,
+                mbid: None,
+            })
+            .collect::<Vec<_>>();
+        let top = top_5_listened_artists(&listens);
+        let mut artists = top.keys().cloned().collect::<Vec<_>>();
+        artists.sort();
+        assert_eq!(artists, vec!["a", "b", "c", "d", "e"]);
+    }
+
+    #[test]
+    fn days_starting_in_a_dst_gap_are_one_bucket() {
+        // midnight doesnt exist on 2023-10-01 in asuncion, the day starts at 01:00 -03
+        let tz = Tz::America__Asuncion;
+        let morning = 1_696_132_800 + 60 * 60;
+        let evening = 1_696_132_800 + 18 * 60 * 60;
+        assert_eq!(Granularity::Day.bucket(tz, morning), 1_696_132_800);
+        assert_eq!(Granularity::Day.bucket(tz, evening), 1_696_132_800);
+    }
 }
@@ -0,0 +1,47 @@
+use crate::cache::{CacheType, MetadataCache};
+use serde_json::Value;
+use std::error::Error;
+
+const CACHE_USAGE: &str = "usage: lastfmwrapped cache <command>
+    stats
+    list <duration|genre> [search]
+    get <duration|genre> <key>
+    set <duration|genre> <key> <json value>
+    delete <duration|genre> <key>
+    export <duration|genre>";
+
+// lastfmwrapped cache ..., the same operations as the /api/cache endpoints
+pub fn cache_command(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let arg = |i: usize| args.get(i).map(String::as_str).ok_or(CACHE_USAGE);
+    let ctype = || -> Result<CacheType, Box<dyn Error>> { Ok(CacheType::from_name(arg(1)?)?) };
+    let cache = MetadataCache::open()?;
+
+    match arg(0)? {
+        "stats" => println!("{}", serde_json::to_string_pretty(&cache.stats()?)?),
+        "list" => {
+            let search = args.get(2).map(String::as_str).unwrap_or("");
+            for e in cache.list(ctype()?, search, -1, 0)? {
+                println!("{}\t{}", e.key, e.value);
+            }
+        }
+        "get" => match cache.lookup(ctype()?, arg(2)?)? {
+            Some(e) => println!("{}", serde_json::to_string_pretty(&e)?),
+            None => return Err("no such entry".into()),
+        },
+        "set" => {
+            let value: Value = serde_json::from_str(arg(3)?)?;
+            cache.set(ctype()?, arg(2)?, &value)?;
+        }
+        "delete" => {
+            if !cache.delete(ctype()?, arg(2)?)? {
+                return Err("no such entry".into());
+            }
+        }
+        "export" => println!(
+            "{}",
+            serde_json::to_string_pretty(&cache.export(ctype()?)?)?
+        ),
+        _ => return Err(CACHE_USAGE.into()),
+    }
+    Ok(())
+}
@@ -0,0 +1,142 @@
+use crate::{
+    error::WrappedErrors,
+    metadata::{BoxFuture, MetadataProvider, TrackRef},
+    musicbrainz::MusicBrainzClient,
+};
+use dotenvy;
+use reqwest::StatusCode;
+use serde_json::Value;
+use std::env;
+
+const DEFAULT_BASE_URL: &str = "https://coverartarchive.org";
+// a recording can be on dozens of releases, most of them share the same front cover anyway
+const MAX_RELEASES: usize = 3;
+
+// front covers by musicbrainz release, releases are found through musicbrainz
+#[derive(Clone)]
+pub struct CoverArtArchive {
+    http: reqwest::Client,
+    base_url: String,
+    musicbrainz: MusicBrainzClient,
+}
+
+impl CoverArtArchive {
+    // shares the musicbrainz http client and with it the user agent
+    pub fn new(base_url: &str, musicbrainz: MusicBrainzClient) -> Self {
+        Self {
+            http: musicbrainz.http().clone(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            musicbrainz,
+        }
+    }
+
+    // COVERARTARCHIVE_BASE_URL can point this at a mock server
+    pub fn from_env() -> Self {
+        let _ = dotenvy::dotenv();
+        let base_url = env::var("COVERARTARCHIVE_BASE_URL").unwrap_or(DEFAULT_BASE_URL.to_string());
+        Self::new(&base_url, MusicBrainzClient::from_env())
+    }
+
+    // url of the releases front cover, None when it has no art
+    pub async fn front_cover(&self, release_id: &str) -> Result<Option<String>, WrappedErrors> {
+        let resp = self
+            .http
+            .get(format!("{}/release/{}", self.base_url, release_id))
+            .send()
+            .await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let art = resp.error_for_status()?.json::<Value>().await?;
+        let images = art["images"].as_array().cloned().unwrap_or_default();
+        Ok(images
+            .iter()
+            .find(|i| i["front"].as_bool() == Some(true))
+            .and_then(|i| {
+                i["thumbnails"]["500"]
+                    .as_str()
+                    .or(i["thumbnails"]["large"].as_str())
+                    .or(i["image"].as_str())
+            })
+            .map(String::from))
+    }
+}
+
+impl MetadataProvider for CoverArtArchive {
+    fn name(&self) -> &'static str {
+        "coverartarchive"
+    }
+
+    fn album_art<'a>(
+        &'a self,
+        track: TrackRef<'a>,
+    ) -> BoxFuture<'a, Result<Option<String>, WrappedErrors>> {
+        Box::pin(async move {
+            let releases = self.musicbrainz.release_ids(track).await?;
+            // one release failing shouldnt keep the next ones from being tried
+            let mut failed = None;
+            for release in releases.iter().take(MAX_RELEASES) {
+                match self.front_cover(release).await {
+                    Ok(Some(url)) => return Ok(Some(url)),
+                    Ok(None) => continue,
+                    Err(e) => {
+                        log::warn!("couldnt fetch the cover of release {}: {}", release, e);
+                        failed.get_or_insert(e);
+                    }
+                }
+            }
+            match failed {
+                Some(e) => Err(e),
+                None => Ok(None),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    #[tokio::test]
+    async fn tries_the_next_release_when_one_has_no_art() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/ws/2/recording/r1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "title": "Song",
+                "releases": [{"id": "bare"}, {"id": "covered"}]
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/release/bare"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/release/covered"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"images": [
+                {"front": false, "image": "back.jpg", "thumbnails": {}},
+                {"front": true, "image": "front.jpg", "thumbnails": {"500": "front-500.jpg"}}
+            ]})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let musicbrainz = MusicBrainzClient::new(&format!("{}/ws/2", server.uri()), "test");
+        let art = CoverArtArchive::new(&server.uri(), musicbrainz)
+            .album_art(TrackRef {
+                artist: "Artist",
+                title: "Song",
+                mbid: Some("r1"),
+                duration_ms: None,
+            })
+            .await;
+        assert!(matches!(art, Ok(Some(url)) if url == "front-500.jpg"));
+    }
+}
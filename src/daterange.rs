@@ -0,0 +1,420 @@
+use chrono::{DateTime, Datelike, Local, Months, NaiveDate, NaiveTime, TimeDelta, TimeZone};
+use chrono_tz::Tz;
+use itertools::Itertools;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum DateRangeErrors {
+    InvalidYear,
+    InvalidTimeZone,
+    MissingBound,
+    EmptyRange,
+    OutOfRange,
+    RangeTooLong,
+    InvalidPeriods,
+    TooManyPeriods,
+}
+
+impl fmt::Display for DateRangeErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for DateRangeErrors {}
+
+// nothing was scrobbled before 1970, and past 9999 chrono cant step months forward safely
+const MIN_TIMESTAMP: i64 = 0;
+const MAX_TIMESTAMP: i64 = 253_402_300_800;
+// longest range a query can ask for, last.fm has only been around since 2002
+const MAX_SPAN: i64 = 25 * 366 * 24 * 60 * 60;
+// most genre evolution slides one request can get, months over a long range would be hundreds
+pub const MAX_PERIODS: usize = 24;
+
+// unix timestamps, from is inclusive and to is exclusive
+// tz is used for day boundaries and for rendering dates
+#[derive(Clone, Copy, Debug)]
+pub struct DateRange {
+    pub from: i64,
+    pub to: i64,
+    pub tz: Tz,
+}
+
+impl DateRange {
+    pub fn new(from: i64, to: i64, tz: Tz) -> Result<Self, DateRangeErrors> {
+        if from >= to {
+            return Err(DateRangeErrors::EmptyRange);
+        }
+        if from < MIN_TIMESTAMP || to > MAX_TIMESTAMP {
+            return Err(DateRangeErrors::OutOfRange);
+        }
+        Ok(Self { from, to, tz })
+    }
+
+    // january 1st to january 1st at local midnight
+    pub fn year(year: i32, tz: Tz) -> Result<Self, DateRangeErrors> {
+        let new_year =
+            |year| NaiveDate::from_ymd_opt(year, 1, 1).ok_or(DateRangeErrors::InvalidYear);
+        let from = start_of_day(tz, new_year(year)?);
+        let to = start_of_day(tz, new_year(year + 1)?);
+        Self::new(from.timestamp(), to.timestamp(), tz)
+    }
+
+    pub fn current_year(tz: Tz) -> Self {
+        Self::year(Local::now().with_timezone(&tz).year(), tz).unwrap()
+    }
+
+    // from/to takes priority over year, nothing given means the current year
+    pub fn from_query(
+        year: Option<i32>,
+        from: Option<i64>,
+        to: Option<i64>,
+        tz: Tz,
+    ) -> Result<Self, DateRangeErrors> {
+        match (from, to, year) {
+            (Some(f), Some(t), _) if t.saturating_sub(f) > MAX_SPAN => {
+                Err(DateRangeErrors::RangeTooLong)
+            }
+            (Some(f), Some(t), _) => Self::new(f, t, tz),
+            (Some(_), None, _) | (None, Some(_), _) => Err(DateRangeErrors::MissingBound),
+            (None, None, Some(y)) => Self::year(y, tz),
+            (None, None, None) => Ok(Self::current_year(tz)),
+        }
+    }
+
+    pub fn start(&self) -> DateTime<Tz> {
+        self.tz.timestamp_opt(self.from, 0).unwrap()
+    }
+
+    // first day of the next month from the list at local midnight (or when that day starts if
+    // midnight falls in a dst gap), after start
+    fn next_boundary(&self, start: DateTime<Tz>, months: &[u32]) -> DateTime<Tz> {
+        let mut month = start.date_naive().with_day(1).unwrap();
+        loop {
+            month = month.checked_add_months(Months::new(1)).unwrap();
+            if months.contains(&month.month()) {
+                break;
+            }
+        }
+        start_of_day(self.tz, month)
+    }
+
+    // calendar windows that start on one of months, the first and last are cut short at from
+    // and to
+    fn split(&self, months: &[u32]) -> Vec<DateRange> {
+        let mut windows = Vec::new();
+        let mut start = self.start();
+        while start.timestamp() < self.to {
+            let end = self.next_boundary(start, months);
+            windows.push(DateRange {
+                from: start.timestamp(),
+                to: end.timestamp().min(self.to),
+                tz: self.tz,
+            });
+            start = end;
+        }
+        windows
+    }
+
+    // the last moment still inside the range
+    pub fn end(&self) -> DateTime<Tz> {
+        self.tz.timestamp_opt(self.to - 1, 0).unwrap()
+    }
+
+    // the part of self that is also in other, None when they dont overlap
+    pub fn intersect(&self, other: &DateRange) -> Option<DateRange> {
+        let (from, to) = (self.from.max(other.from), self.to.min(other.to));
+        (from < to).then_some(DateRange {
+            from,
+            to,
+            tz: self.tz,
+        })
+    }
+}
+
+// how the genre evolution slides cut up the range
+#[derive(Clone, Debug)]
+pub enum Periods {
+    Months,
+    // january to march, april to june...
+    Quarters,
+    // meteorological northern seasons, winter is december to february
+    Seasons,
+    Custom(Vec<DateRange>),
+}
+
+// one slide worth of the range, label is what the slide calls it
+#[derive(Clone, Debug)]
+pub struct Period {
+    pub label: String,
+    pub range: DateRange,
+}
+
+fn season(month: u32) -> &'static str {
+    match month {
+        3..=5 => "Spring",
+        6..=8 => "Summer",
+        9..=11 => "Autumn",
+        _ => "Winter",
+    }
+}
+
+impl Periods {
+    // kind is months, quarters, seasons or comma separated from..to unix timestamp pairs,
+    // at most MAX_PERIODS of them
+    pub fn from_name(kind: &str, tz: Tz) -> Result<Self, DateRangeErrors> {
+        match kind {
+            "months" => Ok(Self::Months),
+            "quarters" => Ok(Self::Quarters),
+            "seasons" => Ok(Self::Seasons),
+            custom if custom.split(',').count() > MAX_PERIODS => {
+                Err(DateRangeErrors::TooManyPeriods)
+            }
+            custom => custom
+                .split(',')
+                .map(|pair| {
+                    let (from, to) = pair
+                        .split_once("..")
+                        .ok_or(DateRangeErrors::InvalidPeriods)?;
+                    let from = from
+                        .trim()
+                        .parse()
+                        .map_err(|_| DateRangeErrors::InvalidPeriods)?;
+                    let to = to
+                        .trim()
+                        .parse()
+                        .map_err(|_| DateRangeErrors::InvalidPeriods)?;
+                    DateRange::new(from, to, tz)
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(Self::Custom),
+        }
+    }
+
+    // every period in range with a label made from its own dates, the year is only added when
+    // the labels would be ambiguous without it. custom periods are cut down to the part inside
+    // range, the listens outside it arent fetched
+    pub fn split(&self, range: DateRange) -> Vec<Period> {
+        let windows = match self {
+            Self::Months => range.split(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]),
+            Self::Quarters => range.split(&[1, 4, 7, 10]),
+            Self::Seasons => range.split(&[3, 6, 9, 12]),
+            Self::Custom(ranges) => ranges.iter().filter_map(|r| r.intersect(&range)).collect(),
+        };
+        let labels = windows.iter().map(|w| self.label(w)).collect::<Vec<_>>();
+        let ambiguous = labels.iter().unique().count() < labels.len()
+            || windows.iter().map(|w| w.start().year()).unique().count() > 1;
+        // custom periods can end in another year than they start
+        let spans_years = windows
+            .iter()
+            .flat_map(|w| [w.start().year(), w.end().year()])
+            .unique()
+            .count()
+            > 1;
+        windows
+            .into_iter()
+            .zip(labels)
+            .map(|(w, label)| {
+                let start = w.start();
+                let label = match self {
+                    Self::Custom(_) if spans_years => format!(
+                        "{} to {}",
+                        start.format("%b %-d %Y"),
+                        w.end().format("%b %-d %Y")
+                    ),
+                    Self::Custom(_) => label,
+                    _ if !ambiguous => label,
+                    // winter runs from one year into the next
+                    Self::Seasons if start.month() <= 2 => {
+                        format!("{} {}/{:02}", label, start.year() - 1, start.year() % 100)
+                    }
+                    Self::Seasons if start.month() == 12 => {
+                        format!("{} {}/{:02}", label, start.year(), (start.year() + 1) % 100)
+                    }
+                    _ => format!("{} {}", label, start.year()),
+                };
+                Period { label, range: w }
+            })
+            .collect()
+    }
+
+    fn label(&self, window: &DateRange) -> String {
+        let (start, end) = (window.start(), window.end());
+        match self {
+            Self::Months => start.format("%B").to_string(),
+            Self::Quarters if start.month() == end.month() => start.format("%B").to_string(),
+            Self::Quarters => format!("{} to {}", start.format("%B"), end.format("%B")),
+            Self::Seasons => season(start.month()).to_string(),
+            Self::Custom(_) => format!("{} to {}", start.format("%b %-d"), end.format("%b %-d")),
+        }
+    }
+
+    // count periods spread evenly over the whole lot, january, may and september for 3 months of
+    // a full year. None or more than there are keeps all of them
+    pub fn sample(periods: Vec<Period>, count: Option<usize>) -> Vec<Period> {
+        let len = periods.len();
+        match count {
+            Some(count) if count < len => (0..count)
+                .map(|i| periods[i * len / count].clone())
+                .collect(),
+            _ => periods,
+        }
+    }
+}
+
+// local midnight, or the first moment of the day when a dst change skips midnight
+// (america/asuncion on 2023-10-01 goes from 23:59 straight to 01:00)
+pub fn start_of_day(tz: Tz, date: NaiveDate) -> DateTime<Tz> {
+    let midnight = date.and_time(NaiveTime::MIN);
+    // samoa skipped a whole day once, so look up to a day ahead
+    (0..=24 * 60)
+        .find_map(|m| {
+            tz.from_local_datetime(&(midnight + TimeDelta::minutes(m)))
+                .earliest()
+        })
+        .unwrap_or_else(|| tz.from_utc_datetime(&midnight))
+}
+
+pub fn parse_timezone(name: &str) -> Result<Tz, DateRangeErrors> {
+    name.parse::<Tz>()
+        .map_err(|_| DateRangeErrors::InvalidTimeZone)
+}
+
+// last.fm only gives us a country, so this picks the zone most of its people live in
+pub fn country_timezone(country: &str) -> Option<Tz> {
+    let tz = match country {
+        "Argentina" => Tz::America__Argentina__Buenos_Aires,
+        "Australia" => Tz::Australia__Sydney,
+        "Austria" => Tz::Europe__Vienna,
+        "Belarus" => Tz::Europe__Minsk,
+        "Belgium" => Tz::Europe__Brussels,
+        "Brazil" => Tz::America__Sao_Paulo,
+        "Canada" => Tz::America__Toronto,
+        "Chile" => Tz::America__Santiago,
+        "China" => Tz::Asia__Shanghai,
+        "Colombia" => Tz::America__Bogota,
+        "Czech Republic" | "Czechia" => Tz::Europe__Prague,
+        "Denmark" => Tz::Europe__Copenhagen,
+        "Finland" => Tz::Europe__Helsinki,
+        "France" => Tz::Europe__Paris,
+        "Germany" => Tz::Europe__Berlin,
+        "Greece" => Tz::Europe__Athens,
+        "Hungary" => Tz::Europe__Budapest,
+        "India" => Tz::Asia__Kolkata,
+        "Indonesia" => Tz::Asia__Jakarta,
+        "Ireland" => Tz::Europe__Dublin,
+        "Israel" => Tz::Asia__Jerusalem,
+        "Italy" => Tz::Europe__Rome,
+        "Japan" => Tz::Asia__Tokyo,
+        "Kazakhstan" => Tz::Asia__Almaty,
+        "Korea, Republic of" | "South Korea" => Tz::Asia__Seoul,
+        "Mexico" => Tz::America__Mexico_City,
+        "Netherlands" => Tz::Europe__Amsterdam,
+        "New Zealand" => Tz::Pacific__Auckland,
+        "Norway" => Tz::Europe__Oslo,
+        "Philippines" => Tz::Asia__Manila,
+        "Poland" => Tz::Europe__Warsaw,
+        "Portugal" => Tz::Europe__Lisbon,
+        "Romania" => Tz::Europe__Bucharest,
+        "Russian Federation" | "Russia" => Tz::Europe__Moscow,
+        "Singapore" => Tz::Asia__Singapore,
+        "Spain" => Tz::Europe__Madrid,
+        "Sweden" => Tz::Europe__Stockholm,
+        "Switzerland" => Tz::Europe__Zurich,
+        "Taiwan" => Tz::Asia__Taipei,
+        "Thailand" => Tz::Asia__Bangkok,
+        "Turkey" | "Türkiye" => Tz::Europe__Istanbul,
+        "Ukraine" => Tz::Europe__Kyiv,
+        "United Kingdom" => Tz::Europe__London,
+        "United States" => Tz::America__New_York,
+        "Vietnam" | "Viet Nam" => Tz::Asia__Ho_Chi_Minh,
+        _ => return None,
+    };
+    Some(tz)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_bounds_chrono_cant_step_through() {
+        let range = DateRange::from_query(None, Some(-100_000_000_000_000), Some(0), Tz::UTC);
+        assert!(matches!(range, Err(DateRangeErrors::RangeTooLong)));
+        let range = DateRange::from_query(None, Some(0), Some(i64::MAX), Tz::UTC);
+        assert!(matches!(range, Err(DateRangeErrors::RangeTooLong)));
+        let range = DateRange::new(-10, 0, Tz::UTC);
+        assert!(matches!(range, Err(DateRangeErrors::OutOfRange)));
+        assert!(matches!(
+            DateRange::year(10_000, Tz::UTC),
+            Err(DateRangeErrors::OutOfRange)
+        ));
+    }
+
+    #[test]
+    fn months_can_start_in_a_dst_gap() {
+        // asuncion went from 23:59 on sep 30 2023 straight to 01:00 on oct 1
+        let periods = Periods::Months.split(DateRange::year(2023, Tz::America__Asuncion).unwrap());
+        assert_eq!(periods.len(), 12);
+        assert_eq!(periods[9].label, "October");
+        assert_eq!(periods[9].range.from, 1_696_132_800);
+        assert_eq!(periods[8].range.to, periods[9].range.from);
+    }
+
+    #[test]
+    fn accepts_a_normal_year() {
+        let range = DateRange::from_query(Some(2024), None, None, Tz::UTC).unwrap();
+        assert_eq!(range.from, 1_704_067_200);
+        assert_eq!(range.to, 1_735_689_600);
+    }
+
+    fn labels(periods: &str, range: DateRange) -> Vec<String> {
+        Periods::from_name(periods, Tz::UTC)
+            .unwrap()
+            .split(range)
+            .into_iter()
+            .map(|p| p.label)
+            .collect()
+    }
+
+    #[test]
+    fn custom_periods_are_cut_to_the_range() {
+        let year = DateRange::year(2024, Tz::UTC).unwrap();
+        // mar 1 - apr 1, dec 1 2023 - jan 15 2024 and all of 2025
+        let periods = Periods::from_name(
+            "1709251200..1711929600,1701388800..1705276800,1735689600..1767225600",
+            Tz::UTC,
+        )
+        .unwrap()
+        .split(year);
+        assert_eq!(periods.len(), 2);
+        assert_eq!(periods[1].range.from, year.from);
+        assert_eq!(periods[1].range.to, 1_705_276_800);
+    }
+
+    #[test]
+    fn custom_labels_add_the_year_when_periods_span_years() {
+        let year = DateRange::year(2024, Tz::UTC).unwrap();
+        assert_eq!(
+            labels("1709251200..1711929600", year),
+            vec!["Mar 1 to Mar 31"]
+        );
+        let two_years = DateRange::new(year.from, 1_767_225_600, Tz::UTC).unwrap();
+        assert_eq!(
+            labels("1733011200..1736899200", two_years),
+            vec!["Dec 1 2024 to Jan 14 2025"]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_custom_periods() {
+        for periods in ["1709251200-1711929600", "1711929600..1709251200", "a..b"] {
+            assert!(Periods::from_name(periods, Tz::UTC).is_err(), "{}", periods);
+        }
+        let too_many = vec!["0..1"; MAX_PERIODS + 1].join(",");
+        assert!(matches!(
+            Periods::from_name(&too_many, Tz::UTC),
+            Err(DateRangeErrors::TooManyPeriods)
+        ));
+    }
+}
@@ -2,5 +2,6 @@
 pub struct Defaults;
 
 impl Defaults {
-    pub const BLACK_IMAGE: &str = "{\"url\": \"https://files.catbox.moe/qo6gb7.png\"}";
+    // drawn in place of covers and artist images nobody has
+    pub const BLACK_IMAGE: &str = "imgs/black.png";
 }
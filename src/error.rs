@@ -0,0 +1,208 @@
+use crate::{
+    cache::CacheErrors, daterange::DateRangeErrors, jobs::JobsErrors, lfm::UnprocessableErrors,
+    source::SourceErrors,
+};
+use rocket::{
+    http::Status,
+    request::Request,
+    response::{self, Responder, Response},
+};
+use serde_json::{json, Value};
+use std::{error::Error, fmt};
+
+// every error an endpoint can answer with, the status code follows from the variant
+#[derive(Debug)]
+pub enum WrappedErrors {
+    Unprocessable(UnprocessableErrors),
+    // bad query parameters or an unknown source/format/cache type
+    InvalidRequest(String),
+    NotFound(String),
+    // last.fm, spotify or listenbrainz failed or answered with something unusable
+    Upstream(String),
+    // we or an upstream api are too busy right now
+    RateLimited(String),
+    // the client went over a limit of its own, e.g. too many jobs for one user
+    TooManyRequests(String),
+    // an uploaded export that couldnt be read
+    Parse(String),
+    Render(String),
+    // sqlite and everything else that is our own fault
+    Internal(String),
+}
+
+impl WrappedErrors {
+    pub fn status(&self) -> Status {
+        match self {
+            WrappedErrors::Unprocessable(UnprocessableErrors::UserNotFound) => Status::NotFound,
+            WrappedErrors::Unprocessable(_) => Status::UnprocessableEntity,
+            WrappedErrors::InvalidRequest(_) | WrappedErrors::Parse(_) => Status::BadRequest,
+            WrappedErrors::NotFound(_) => Status::NotFound,
+            WrappedErrors::Upstream(_) => Status::BadGateway,
+            WrappedErrors::RateLimited(_) => Status::ServiceUnavailable,
+            WrappedErrors::TooManyRequests(_) => Status::TooManyRequests,
+            WrappedErrors::Render(_) | WrappedErrors::Internal(_) => Status::InternalServerError,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            WrappedErrors::Unprocessable(_) => "unprocessable",
+            WrappedErrors::InvalidRequest(_) => "invalid_request",
+            WrappedErrors::NotFound(_) => "not_found",
+            WrappedErrors::Upstream(_) => "upstream",
+            WrappedErrors::RateLimited(_) => "rate_limited",
+            WrappedErrors::TooManyRequests(_) => "too_many_requests",
+            WrappedErrors::Parse(_) => "parse",
+            WrappedErrors::Render(_) => "render",
+            WrappedErrors::Internal(_) => "internal",
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        error_body(self.status(), self.kind(), &self.to_string())
+    }
+}
+
+// unprocessable errors keep printing as UserNotFound etc, the frontend matches on those
+impl fmt::Display for WrappedErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WrappedErrors::Unprocessable(e) => write!(f, "{}", e),
+            WrappedErrors::InvalidRequest(m)
+            | WrappedErrors::NotFound(m)
+            | WrappedErrors::Upstream(m)
+            | WrappedErrors::RateLimited(m)
+            | WrappedErrors::TooManyRequests(m)
+            | WrappedErrors::Parse(m)
+            | WrappedErrors::Render(m)
+            | WrappedErrors::Internal(m) => write!(f, "{}", m),
+        }
+    }
+}
+
+impl Error for WrappedErrors {}
+
+fn error_body(status: Status, kind: &str, message: &str) -> Value {
+    json!({ "error": message, "kind": kind, "status": status.code })
+}
+
+impl<'r> Responder<'r, 'static> for WrappedErrors {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        Response::build_from(self.to_json().respond_to(req)?)
+            .status(self.status())
+            .ok()
+    }
+}
+
+// same body for errors rocket answers itself, e.g. a failed guard or an unknown route
+#[catch(default)]
+pub fn default_catcher(status: Status, _req: &Request) -> Value {
+    error_body(
+        status,
+        if status.code == 404 {
+            "not_found"
+        } else if status.code < 500 {
+            "invalid_request"
+        } else {
+            "internal"
+        },
+        status.reason().unwrap_or("unknown error"),
+    )
+}
+
+impl From<UnprocessableErrors> for WrappedErrors {
+    fn from(e: UnprocessableErrors) -> Self {
+        WrappedErrors::Unprocessable(e)
+    }
+}
+
+// a user with too many jobs of their own is told to slow down, a full server to try again later
+impl From<JobsErrors> for WrappedErrors {
+    fn from(e: JobsErrors) -> Self {
+        match e {
+            JobsErrors::TooManyJobsForUser => WrappedErrors::TooManyRequests(e.to_string()),
+            JobsErrors::TooManyJobs => WrappedErrors::RateLimited(e.to_string()),
+        }
+    }
+}
+
+impl From<DateRangeErrors> for WrappedErrors {
+    fn from(e: DateRangeErrors) -> Self {
+        WrappedErrors::InvalidRequest(e.to_string())
+    }
+}
+
+impl From<SourceErrors> for WrappedErrors {
+    fn from(e: SourceErrors) -> Self {
+        WrappedErrors::InvalidRequest(e.to_string())
+    }
+}
+
+impl From<CacheErrors> for WrappedErrors {
+    fn from(e: CacheErrors) -> Self {
+        WrappedErrors::InvalidRequest(e.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for WrappedErrors {
+    fn from(e: rusqlite::Error) -> Self {
+        WrappedErrors::Internal(e.to_string())
+    }
+}
+
+// a blocking task that panicked
+impl From<tokio::task::JoinError> for WrappedErrors {
+    fn from(e: tokio::task::JoinError) -> Self {
+        WrappedErrors::Internal(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for WrappedErrors {
+    fn from(e: std::io::Error) -> Self {
+        WrappedErrors::Internal(e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for WrappedErrors {
+    fn from(e: reqwest::Error) -> Self {
+        match e.status() {
+            Some(s) if s.as_u16() == 429 => WrappedErrors::RateLimited(e.to_string()),
+            Some(s) if s.as_u16() == 404 => WrappedErrors::NotFound(e.to_string()),
+            _ => WrappedErrors::Upstream(e.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for WrappedErrors {
+    fn from(e: serde_json::Error) -> Self {
+        WrappedErrors::Parse(e.to_string())
+    }
+}
+
+impl From<image::ImageError> for WrappedErrors {
+    fn from(e: image::ImageError) -> Self {
+        WrappedErrors::Render(e.to_string())
+    }
+}
+
+// calculations return Box<dyn Error>, most of what ends up in there comes from the listen sources
+impl From<Box<dyn Error>> for WrappedErrors {
+    fn from(e: Box<dyn Error>) -> Self {
+        let e = match e.downcast::<WrappedErrors>() {
+            Ok(e) => return *e,
+            Err(e) => e,
+        };
+        let e = match e.downcast::<DateRangeErrors>() {
+            Ok(e) => return WrappedErrors::from(*e),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<rusqlite::Error>() {
+            Ok(e) => return WrappedErrors::from(*e),
+            Err(e) => e,
+        };
+        match e.downcast::<reqwest::Error>() {
+            Ok(e) => WrappedErrors::from(*e),
+            Err(e) => WrappedErrors::Upstream(e.to_string()),
+        }
+    }
+}
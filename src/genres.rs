@@ -0,0 +1,176 @@
+use regex::Regex;
+use std::{collections::HashMap, sync::OnceLock};
+
+// most genres kept per artist after merging every provider
+pub const MAX_GENRES: usize = 10;
+
+// genre and how sure a provider is of it, 0 to 1
+pub type WeightedGenres = Vec<(String, f64)>;
+
+// tags people put on everything that say nothing about how the music sounds
+const NOT_GENRES: &[&str] = &[
+    "seen live",
+    "favorites",
+    "favourites",
+    "favorite",
+    "favourite",
+    "my favorite",
+    "favorite songs",
+    "favorite artists",
+    "love",
+    "loved",
+    "love at first listen",
+    "awesome",
+    "beautiful",
+    "amazing",
+    "cool",
+    "good",
+    "best",
+    "albums i own",
+    "spotify",
+    "under 2000 listeners",
+    "female vocalists",
+    "male vocalists",
+    "female vocalist",
+    "male vocalist",
+    "female",
+    "male",
+    "all",
+    "check out",
+    "to listen",
+    "vinyl",
+    "american",
+    "british",
+    "english",
+    "uk",
+    "usa",
+    "us",
+    "canadian",
+    "australian",
+    "irish",
+    "scottish",
+    "german",
+    "french",
+    "swedish",
+    "norwegian",
+    "finnish",
+    "danish",
+    "icelandic",
+    "dutch",
+    "belgian",
+    "italian",
+    "spanish",
+    "portuguese",
+    "brazilian",
+    "mexican",
+    "argentinian",
+    "russian",
+    "polish",
+    "japanese",
+    "korean",
+    "chinese",
+    "taiwanese",
+    "indian",
+    "african",
+    "nigerian",
+    "south african",
+    "new zealand",
+];
+
+// 1999, 2010s, 80s, '90s, 00s
+fn year_tag() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"^'?(\d{2}|\d{4})s?$").unwrap())
+}
+
+fn clean(tag: &str) -> String {
+    tag.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+pub fn is_genre(tag: &str) -> bool {
+    let tag = clean(tag);
+    !tag.is_empty() && !NOT_GENRES.contains(&tag.as_str()) && !year_tag().is_match(&tag)
+}
+
+// every provider gives genres weighted 0 to 1, weights of the same genre add up across providers
+// and the heaviest come first
+pub fn rank(sources: Vec<WeightedGenres>) -> Vec<String> {
+    let mut weights: HashMap<String, f64> = HashMap::new();
+    for (genre, weight) in sources.into_iter().flatten() {
+        if weight <= 0.0 || !is_genre(&genre) {
+            continue;
+        }
+        *weights.entry(clean(&genre)).or_insert(0.0) += weight;
+    }
+    let mut ranked = weights.into_iter().collect::<Vec<_>>();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    ranked
+        .into_iter()
+        .take(MAX_GENRES)
+        .map(|(genre, _)| genre)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weighted(genres: &[(&str, f64)]) -> WeightedGenres {
+        genres.iter().map(|(g, w)| (g.to_string(), *w)).collect()
+    }
+
+    #[test]
+    fn filler_tags_arent_genres() {
+        for tag in [
+            "seen live",
+            "Female Vocalists",
+            "  favorite   songs ",
+            "",
+            "british",
+        ] {
+            assert!(!is_genre(tag), "{:?}", tag);
+        }
+        for tag in ["indie rock", "Hip-Hop", "uk garage", "80s pop"] {
+            assert!(is_genre(tag), "{:?}", tag);
+        }
+    }
+
+    #[test]
+    fn year_tags_arent_genres() {
+        for tag in ["1999", "2010s", "80s", "'90s", "00s"] {
+            assert!(!is_genre(tag), "{:?}", tag);
+        }
+        for tag in ["19999", "s", "90ss"] {
+            assert!(is_genre(tag), "{:?}", tag);
+        }
+    }
+
+    #[test]
+    fn weights_add_up_across_providers() {
+        let lastfm = weighted(&[("Rock", 1.0), ("pop", 0.6), ("seen live", 1.0)]);
+        let spotify = weighted(&[("pop", 0.6), ("jazz", 1.0)]);
+        let musicbrainz = weighted(&[("jazz", 0.1), ("metal", 0.0)]);
+        assert_eq!(
+            rank(vec![lastfm, spotify, musicbrainz]),
+            vec!["pop", "jazz", "rock"]
+        );
+    }
+
+    #[test]
+    fn ties_and_the_cap_are_decided_by_name() {
+        let many = (0..MAX_GENRES + 2)
+            .rev()
+            .map(|i| (format!("genre {:02}", i), 1.0))
+            .collect();
+        let ranked = rank(vec![many]);
+        assert_eq!(ranked.len(), MAX_GENRES);
+        assert_eq!(ranked[0], "genre 00");
+        assert_eq!(
+            ranked[MAX_GENRES - 1],
+            format!("genre {:02}", MAX_GENRES - 1)
+        );
+    }
+}
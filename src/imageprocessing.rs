@@ -1,19 +1,18 @@
-use crate::calculations::GenreMonths;
+use crate::{calculations::GenreSeries, jobs::Progress, taxonomy};
 use ab_glyph::{FontRef, PxScale};
 use aho_corasick::AhoCorasick;
-use chrono::{TimeZone, Utc};
+use chrono::TimeZone;
+use chrono_tz::Tz;
 use image::{
     imageops::{self, FilterType},
     DynamicImage, ImageReader, Rgba,
 };
 use imageproc::drawing::{draw_text_mut, text_size};
 use itertools::Itertools;
-use rand::seq::SliceRandom;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 use regex::Regex;
-use serde_json::{json, Value};
 use std::error::Error;
 use thousands::Separable;
-use titlecase::titlecase;
 use unicode_truncate::UnicodeTruncateStr;
 
 #[derive(Clone)]
@@ -174,12 +173,13 @@ pub fn minutes_listened(
     total: i64,
     busiest_day: i64,
     busiest_time: i64,
+    tz: Tz,
 ) -> Result<DynamicImage, Box<dyn Error>> {
     let mut img = ImageReader::open("imgs/minuteslistened.png")?.decode()?;
     let fonts = fonts().unwrap();
     let totalscale = PxScale::from(290.0);
     let busiestscale = PxScale::from(50.0);
-    let busiest_day_string = Utc
+    let busiest_day_string = tz
         .timestamp_opt(busiest_day, 0)
         .unwrap()
         .format("%B %_d")
@@ -363,15 +363,52 @@ pub fn top_5_songs(
     Ok(img)
 }
 
-pub fn genre_evolution(months: GenreMonths) -> Result<Vec<DynamicImage>, Box<dyn Error>> {
+// how many of a periods top genres a seed can pick the two on its slide from
+const GENRE_CHOICES: usize = 5;
+
+// the two most scrobbled genres, or with an rng two of the top few weighted by scrobbles.
+// either way they stay in ranked order
+fn pick_genres(ranked: &[(String, i64)], rng: Option<&mut StdRng>) -> Vec<String> {
+    let choices = &ranked[..ranked.len().min(GENRE_CHOICES)];
+    let picked = match rng {
+        Some(rng) => choices
+            .choose_multiple_weighted(rng, 2, |(_, scrobbles)| *scrobbles as f64)
+            .map(|picked| picked.collect::<Vec<_>>())
+            .unwrap_or_default(),
+        None => choices.iter().take(2).collect(),
+    };
+    choices
+        .iter()
+        .filter(|c| picked.contains(c))
+        .map(|(genre, _)| genre.clone())
+        .collect()
+}
+
+// "a", "a and b" or "a, b, and c"
+fn list_artists(artists: &[&String]) -> String {
+    match artists {
+        [] => String::new(),
+        [a] => a.to_string(),
+        [a, b] => format!("{} and {}", a, b),
+        [rest @ .., last] => format!("{}, and {}", rest.iter().join(", "), last),
+    }
+}
+
+// one slide per period, the backgrounds take turns when there are more than three.
+// same periods and seed always draw the same slides, no seed shows each periods top two
+pub fn genre_evolution(
+    series: GenreSeries,
+    seed: Option<u64>,
+    progress: &Progress,
+) -> Result<Vec<DynamicImage>, Box<dyn Error>> {
     let imgs = [
         ImageReader::open("imgs/genreevolution1.png")?.decode()?,
         ImageReader::open("imgs/genreevolution2.png")?.decode()?,
         ImageReader::open("imgs/genreevolution3.png")?.decode()?,
     ];
-    let mut modified_imgs = Vec::with_capacity(3);
-    let mut rng = rand::thread_rng();
-    let mut existing_genres = Vec::new();
+    let mut modified_imgs = Vec::with_capacity(series.periods.len());
+    let mut rng = seed.map(StdRng::seed_from_u64);
+    let mut existing_genres: Vec<String> = Vec::new();
 
     // will probably be more patterns in the future
     let bad_patterns = &["russian", "belarusian"];
@@ -379,68 +416,75 @@ pub fn genre_evolution(months: GenreMonths) -> Result<Vec<DynamicImage>, Box<dyn
 
     let font = fonts()?;
     let genrescale = PxScale::from(288.0);
-    let monthscale = PxScale::from(62.0);
+    let periodscale = PxScale::from(62.0);
     let artistsscale = PxScale::from(48.0);
     let fallbackscale = PxScale::from(48.0);
 
-    #[allow(clippy::needless_range_loop)]
-    for i in 0..=2 {
-        let mut img = imgs[i].clone();
-        let genrehashmap = months.clone().get(i);
-        let artists = genrehashmap.keys().collect::<Vec<&String>>();
-        let top_artists = format!(
-            "Listening to artists like {}, {}, and {}",
-            artists[0], artists[1], artists[2]
-        );
+    for (i, period) in series.periods.iter().enumerate() {
+        let mut img = imgs[i % imgs.len()].clone();
+        let artists = period
+            .genres
+            .artists
+            .iter()
+            .map(|(a, _)| a)
+            .collect::<Vec<&String>>();
+        let top_artists = if artists.is_empty() {
+            String::new()
+        } else {
+            format!("Listening to artists like {}", list_artists(&artists))
+        };
         let top_artists_wrapped = textwrap::wrap(&top_artists, 54);
 
-        let mut genres = genrehashmap
-            .values()
-            .collect::<Vec<&Vec<Value>>>()
+        let ranked = period
+            .genres
+            .genres
             .iter()
-            .map(|x| x.choose(&mut rng).unwrap_or(&json!("")).to_string())
-            .map(|x| {
-                ac.replace_all(&x, &["", ""])
-                    .trim_matches(&['\"', ' '])
-                    .to_string()
+            .map(|g| {
+                let name = ac
+                    .replace_all(&g.genre, &["", ""])
+                    .trim_matches(['\"', ' '])
+                    .to_string();
+                (taxonomy::display_name(&name), g.scrobbles)
             })
-            .map(|x| titlecase(&x))
-            .filter(|x| !existing_genres.contains(x))
-            .filter(|x| !x.is_empty())
-            .collect::<Vec<String>>()
-            .choose_multiple(&mut rng, 2)
+            .filter(|(x, _)| !x.is_empty())
+            .collect::<Vec<_>>();
+        let fresh = ranked
+            .iter()
+            .filter(|(x, _)| !existing_genres.contains(x))
             .cloned()
-            .collect::<Vec<String>>();
-        if genres.len() < 2 {
-            genres.push(existing_genres.choose(&mut rng).unwrap().to_string());
+            .collect::<Vec<_>>();
+        let mut genres = pick_genres(&fresh, rng.as_mut());
+        // a period with nothing new repeats its own top genres rather than leaving a gap
+        for (genre, _) in &ranked {
+            if genres.len() >= 2 {
+                break;
+            }
+            if !genres.contains(genre) {
+                genres.push(genre.clone());
+            }
         }
-        existing_genres.append(&mut genres.clone());
+        existing_genres.extend(genres.iter().cloned());
         existing_genres = existing_genres
             .iter()
             .unique()
             .cloned()
             .collect::<Vec<String>>();
 
-        let monthc = calculate_text_centre(
-            &img,
-            monthscale,
-            &font.medium,
-            &format!("My {}", months.clone().get_month_string(i)),
-        );
-        let genres_wrapped = [
-            textwrap::wrap(&genres[0], 15),
-            textwrap::wrap(&genres[1], 15),
-        ]
-        .concat();
+        let label = format!("My {}", period.period.label);
+        let periodc = calculate_text_centre(&img, periodscale, &font.medium, &label);
+        let genres_wrapped = genres
+            .iter()
+            .flat_map(|g| textwrap::wrap(g, 15))
+            .collect::<Vec<_>>();
 
         draw_text_mut(
             &mut img,
             Rgba([255, 255, 255, 255]),
-            monthc.0,
+            periodc.0,
             519,
-            monthscale,
+            periodscale,
             &font.medium,
-            &format!("My {}", months.clone().get_month_string(i)),
+            &label,
         );
         let mut genrelinexy = (0, 593);
         for i in &genres_wrapped {
@@ -491,6 +535,7 @@ pub fn genre_evolution(months: GenreMonths) -> Result<Vec<DynamicImage>, Box<dyn
             artistlinexy.1 += 50;
         }
         modified_imgs.push(img);
+        progress.advance(1);
     }
 
     Ok(modified_imgs)
@@ -559,3 +604,44 @@ pub fn final_image(
 
     Ok(img)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranked() -> Vec<(String, i64)> {
+        [
+            ("rock", 50),
+            ("pop", 40),
+            ("jazz", 30),
+            ("metal", 20),
+            ("folk", 10),
+            ("punk", 5),
+        ]
+        .iter()
+        .map(|(g, s)| (g.to_string(), *s))
+        .collect()
+    }
+
+    #[test]
+    fn no_seed_picks_the_top_two() {
+        assert_eq!(pick_genres(&ranked(), None), vec!["rock", "pop"]);
+        assert_eq!(pick_genres(&ranked()[..1], None), vec!["rock"]);
+    }
+
+    #[test]
+    fn the_same_seed_picks_the_same_genres() {
+        for seed in 0..20 {
+            let picked = pick_genres(&ranked(), Some(&mut StdRng::seed_from_u64(seed)));
+            let again = pick_genres(&ranked(), Some(&mut StdRng::seed_from_u64(seed)));
+            assert_eq!(picked, again);
+            assert_eq!(picked.len(), 2);
+            // only the top few are picked from, and they stay in ranked order
+            let positions = picked
+                .iter()
+                .map(|p| ranked().iter().position(|(g, _)| g == p).unwrap())
+                .collect::<Vec<_>>();
+            assert!(positions[0] < positions[1] && positions[1] < GENRE_CHOICES);
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use crate::error::WrappedErrors;
+use image::{
+    codecs::{jpeg::JpegEncoder, webp::WebPEncoder},
+    DynamicImage, ImageFormat,
+};
+use rocket::{
+    http::{ContentType, Status},
+    request::{FromRequest, Outcome, Request},
+    response::{self, Responder, Response},
+};
+use serde_json::Value;
+use std::io::Cursor;
+
+const DEFAULT_QUALITY: u8 = 85;
+// the slides only change as new scrobbles come in, an hour old image is fine
+const IMAGE_MAX_AGE: u32 = 60 * 60;
+
+// how an image endpoint should answer, picked from ?format= or else the Accept header
+#[derive(Clone, Copy)]
+pub enum ImageEncoding {
+    // base64 inside json like the endpoints always did
+    Json,
+    Png,
+    // quality is 1-100
+    Jpeg(u8),
+    // the image crate only writes lossless webp so there is no quality for it,
+    // ?quality= with ?format=webp is a bad request
+    WebP,
+}
+
+impl ImageEncoding {
+    fn from_name(name: &str, quality: Option<u8>) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "json" => Some(ImageEncoding::Json),
+            "png" => Some(ImageEncoding::Png),
+            "jpeg" | "jpg" => Some(ImageEncoding::Jpeg(quality.unwrap_or(DEFAULT_QUALITY))),
+            "webp" if quality.is_none() => Some(ImageEncoding::WebP),
+            _ => None,
+        }
+    }
+
+    // first type the client lists that we can do, so <img> tags get images and
+    // fetch()/curl with */* keep getting json. webp is skipped when a quality was asked for
+    fn from_accept(req: &Request<'_>, quality: Option<u8>) -> Self {
+        let accept = match req.accept() {
+            Some(a) => a,
+            None => return ImageEncoding::Json,
+        };
+        for m in accept.iter() {
+            let top = m.top().as_str().to_ascii_lowercase();
+            let sub = m.sub().as_str().to_ascii_lowercase();
+            match (top.as_str(), sub.as_str()) {
+                ("image", "webp") if quality.is_none() => return ImageEncoding::WebP,
+                ("image", "jpeg") => {
+                    return ImageEncoding::Jpeg(quality.unwrap_or(DEFAULT_QUALITY))
+                }
+                ("image", "png") | ("image", "*") => return ImageEncoding::Png,
+                ("application", "json") | ("*", "*") => return ImageEncoding::Json,
+                _ => continue,
+            }
+        }
+        ImageEncoding::Json
+    }
+
+    fn content_type(self) -> ContentType {
+        match self {
+            ImageEncoding::Json => ContentType::JSON,
+            ImageEncoding::Png => ContentType::PNG,
+            ImageEncoding::Jpeg(_) => ContentType::JPEG,
+            ImageEncoding::WebP => ContentType::WEBP,
+        }
+    }
+
+    fn encode(self, img: &DynamicImage) -> Result<Vec<u8>, image::ImageError> {
+        let mut buffer = Cursor::new(Vec::new());
+        match self {
+            // jpeg has no alpha channel
+            ImageEncoding::Jpeg(quality) => DynamicImage::ImageRgb8(img.to_rgb8())
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut buffer, quality))?,
+            ImageEncoding::WebP => {
+                img.write_with_encoder(WebPEncoder::new_lossless(&mut buffer))?
+            }
+            _ => img.write_to(&mut buffer, ImageFormat::Png)?,
+        }
+        Ok(buffer.into_inner())
+    }
+
+    // json is only built when it was asked for, it needs the image encoded twice otherwise
+    pub fn respond(
+        self,
+        img: DynamicImage,
+        to_json: impl FnOnce(DynamicImage) -> Value,
+    ) -> Result<ImageResponse, WrappedErrors> {
+        match self {
+            ImageEncoding::Json => Ok(ImageResponse::Json(to_json(img))),
+            _ => Ok(ImageResponse::Raw(self.encode(&img)?, self.content_type())),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ImageEncoding {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let quality = req
+            .query_value::<u8>("quality")
+            .and_then(Result::ok)
+            .map(|q| q.clamp(1, 100));
+        match req.query_value::<&str>("format") {
+            Some(Ok(name)) => match ImageEncoding::from_name(name, quality) {
+                Some(encoding) => Outcome::Success(encoding),
+                None => Outcome::Error((Status::BadRequest, ())),
+            },
+            _ => Outcome::Success(ImageEncoding::from_accept(req, quality)),
+        }
+    }
+}
+
+pub enum ImageResponse {
+    Json(Value),
+    Raw(Vec<u8>, ContentType),
+}
+
+impl<'r> Responder<'r, 'static> for ImageResponse {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        match self {
+            ImageResponse::Json(v) => Response::build_from(v.respond_to(req)?)
+                .raw_header("Vary", "Accept")
+                .ok(),
+            ImageResponse::Raw(bytes, content_type) => Response::build()
+                .header(content_type)
+                .raw_header(
+                    "Cache-Control",
+                    format!("public, max-age={}", IMAGE_MAX_AGE),
+                )
+                .raw_header("Vary", "Accept")
+                .sized_body(bytes.len(), Cursor::new(bytes))
+                .ok(),
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use chrono::Utc;
+use dotenvy;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    env, fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+
+// finished jobs are dropped after an hour
+const JOB_TTL: i64 = 60 * 60;
+// slow event stream listeners skip ahead once they fall this far behind
+const EVENT_BUFFER: usize = 64;
+
+#[derive(Debug)]
+pub enum JobsErrors {
+    TooManyJobs,
+    TooManyJobsForUser,
+}
+
+impl fmt::Display for JobsErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for JobsErrors {}
+
+// MAX_RUNNING_JOBS and MAX_USER_JOBS, a full year job can take minutes of api calls
+fn job_limit(var: &str, default: usize) -> usize {
+    let _ = dotenvy::dotenv();
+    env::var(var)
+        .ok()
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(default)
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Clone, Serialize)]
+pub struct JobStatus {
+    pub id: String,
+    pub kind: String,
+    pub username: String,
+    pub state: JobState,
+    pub phase: String,
+    // human readable phase + progress, what the event stream is meant to be shown as
+    pub message: String,
+    pub done: u64,
+    pub total: u64,
+    pub percent: f64,
+    pub error: Option<String>,
+}
+
+struct Job {
+    status: JobStatus,
+    result: Option<Value>,
+    finished_at: Option<i64>,
+    created_at: i64,
+    events: broadcast::Sender<JobStatus>,
+}
+
+// rocket managed state, every generation started through /api/jobs lives here
+#[derive(Clone, Default)]
+pub struct Jobs {
+    inner: Arc<Mutex<HashMap<String, Job>>>,
+}
+
+impl Jobs {
+    // fails when too many jobs are running already, overall or for this user
+    pub fn create(&self, kind: &str, username: &str) -> Result<Progress, JobsErrors> {
+        let id = format!("{:016x}", rand::random::<u64>());
+        let mut jobs = self.inner.lock().unwrap();
+        let now = Utc::now().timestamp();
+        jobs.retain(|_, j| j.finished_at.is_none_or(|f| now - f < JOB_TTL));
+        let running = jobs
+            .values()
+            .filter(|j| j.status.state == JobState::Running)
+            .collect::<Vec<_>>();
+        if running.len() >= job_limit("MAX_RUNNING_JOBS", 8) {
+            return Err(JobsErrors::TooManyJobs);
+        }
+        let running_for_user = running
+            .iter()
+            .filter(|j| j.status.username.eq_ignore_ascii_case(username))
+            .count();
+        if running_for_user >= job_limit("MAX_USER_JOBS", 2) {
+            return Err(JobsErrors::TooManyJobsForUser);
+        }
+        jobs.insert(
+            id.clone(),
+            Job {
+                status: JobStatus {
+                    id: id.clone(),
+                    kind: kind.to_string(),
+                    username: username.to_string(),
+                    state: JobState::Running,
+                    phase: "queued".to_string(),
+                    message: "queued".to_string(),
+                    done: 0,
+                    total: 0,
+                    percent: 0.0,
+                    error: None,
+                },
+                result: None,
+                finished_at: None,
+                created_at: now,
+                events: broadcast::channel(EVENT_BUFFER).0,
+            },
+        );
+        Ok(Progress {
+            jobs: Some(self.clone()),
+            id,
+        })
+    }
+
+    pub fn status(&self, id: &str) -> Option<JobStatus> {
+        self.inner.lock().unwrap().get(id).map(|j| j.status.clone())
+    }
+
+    pub fn result(&self, id: &str) -> Option<Value> {
+        self.inner
+            .lock()
+            .unwrap()
+            .get(id)
+            .and_then(|j| j.result.clone())
+    }
+
+    // current status plus a receiver for every status change after it
+    pub fn subscribe(&self, id: &str) -> Option<(JobStatus, broadcast::Receiver<JobStatus>)> {
+        self.inner
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|j| (j.status.clone(), j.events.subscribe()))
+    }
+
+    // the job most recently started for a user
+    pub fn latest_for(&self, username: &str) -> Option<String> {
+        self.inner
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|j| j.status.username.eq_ignore_ascii_case(username))
+            .max_by_key(|j| j.created_at)
+            .map(|j| j.status.id.clone())
+    }
+
+    // f returns whether the change is worth telling event stream listeners about
+    fn update(&self, id: &str, f: impl FnOnce(&mut Job) -> bool) {
+        if let Some(job) = self.inner.lock().unwrap().get_mut(id) {
+            if f(job) {
+                job.status.message = if job.status.total > 0 {
+                    format!(
+                        "{} {}/{}",
+                        job.status.phase, job.status.done, job.status.total
+                    )
+                } else {
+                    job.status.phase.clone()
+                };
+                // nobody listening is fine
+                let _ = job.events.send(job.status.clone());
+            }
+        }
+    }
+}
+
+// handed down into calculations so long loops can report how far along they are,
+// Progress::none() for plain requests that nobody polls
+#[derive(Clone)]
+pub struct Progress {
+    jobs: Option<Jobs>,
+    id: String,
+}
+
+impl Progress {
+    pub fn none() -> Self {
+        Self {
+            jobs: None,
+            id: String::new(),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Job) -> bool) {
+        if let Some(jobs) = &self.jobs {
+            jobs.update(&self.id, f);
+        }
+    }
+
+    // starts a new phase, total is 0 when it isnt known up front
+    pub fn phase(&self, phase: &str, total: u64) {
+        self.update(|j| {
+            j.status.phase = phase.to_string();
+            j.status.done = 0;
+            j.status.total = total;
+            j.status.percent = 0.0;
+            true
+        });
+    }
+
+    // only sends an event when the whole percentage changes, advance gets called per scrobble
+    pub fn advance(&self, n: u64) {
+        self.update(|j| {
+            let before = j.status.percent as u64;
+            j.status.done += n;
+            if j.status.total > 0 {
+                j.status.percent =
+                    (j.status.done as f64 / j.status.total as f64 * 100.0).min(100.0);
+            }
+            j.status.percent as u64 != before || j.status.done == j.status.total
+        });
+    }
+
+    pub fn finish(&self, result: Value) {
+        self.update(|j| {
+            j.status.state = JobState::Done;
+            j.status.phase = "done".to_string();
+            j.status.percent = 100.0;
+            j.result = Some(result);
+            j.finished_at = Some(Utc::now().timestamp());
+            true
+        });
+    }
+
+    // runs the job in the background and finishes or fails it with the result. a panic fails
+    // it too, otherwise it would stay running forever and never expire
+    pub fn spawn<F, E>(self, task: F)
+    where
+        F: Future<Output = Result<Value, E>> + Send + 'static,
+        E: fmt::Display,
+    {
+        // errors like Box<dyn Error> arent Send, only their message leaves the task
+        let handle = tokio::spawn(async move { task.await.map_err(|e| e.to_string()) });
+        tokio::spawn(async move {
+            match handle.await {
+                Ok(Ok(v)) => self.finish(v),
+                Ok(Err(e)) => self.fail(&e),
+                Err(e) => self.fail(&format!("job crashed: {}", e)),
+            }
+        });
+    }
+
+    pub fn fail(&self, error: &str) {
+        self.update(|j| {
+            j.status.state = JobState::Failed;
+            j.status.error = Some(error.to_string());
+            j.finished_at = Some(Utc::now().timestamp());
+            true
+        });
+    }
+}
@@ -1,7 +1,22 @@
+use crate::{
+    daterange::DateRange,
+    error::WrappedErrors,
+    genres::{self, WeightedGenres},
+    listens::{self, Listen},
+    metadata::{ArtistRef, BoxFuture, MetadataProvider, TrackRef},
+    ratelimit::{backoff, rate_from_env, RateLimiter},
+};
 use dotenvy;
-use lastfm::Client;
-use serde_json::{json, Value};
-use std::{collections::HashMap, env, fmt};
+use serde_json::Value;
+use std::{collections::HashMap, env, fmt, sync::OnceLock};
+
+const DEFAULT_BASE_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+// most scrobbles user.getRecentTracks hands out per page
+const PAGE_SIZE: i64 = 200;
+// attempts per call before the last error is handed back
+const MAX_ATTEMPTS: u32 = 4;
+// tag counts go from 100 for the most used tag down, anything under this is one or two people
+const MIN_TAG_COUNT: i64 = 10;
 
 #[derive(Debug)]
 pub enum UnprocessableErrors {
@@ -16,135 +31,541 @@ impl fmt::Display for UnprocessableErrors {
     }
 }
 
-fn get_api_key() -> String {
-    let _ = dotenvy::dotenv();
-    env::var("API_KEY").expect("Expected an API key in the environment")
-}
-
-pub fn init_client(username: &str) -> Client<String, &str> {
-    let key = get_api_key();
-    Client::builder().api_key(key).username(username).build()
-}
-
-pub async fn get_scrobble_count(client: Client<String, &str>) -> u64 {
-    client.all_tracks().await.unwrap().total_tracks
-}
-
-// returns in miliseconds
-pub async fn get_track_info(artist: &String, title: &String) -> Value {
-    let key = get_api_key();
-    let request_url = format!("http://ws.audioscrobbler.com/2.0/?method=track.getinfo&api_key={}&artist={}&track={}&format=json", key, artist, title);
-    let resp_text = reqwest::get(request_url)
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap();
-    let resp: Value =
-        serde_json::from_str(&resp_text).unwrap_or(json!({ "artist": { "name": "" }, "name": "" }));
-    //println!("{}", resp);
-    resp
-}
-
-pub fn get_track_duration(response: &Value) -> i32 {
-    let duration = response["track"]["duration"]
-        .as_str()
-        .unwrap_or("0")
-        .parse::<i32>()
-        .unwrap_or(0);
-    duration
-}
-
-// tracks over 1 year
-pub async fn fetch_top_5_tracks(username: &String) -> HashMap<String, i32> {
-    let key = get_api_key();
-    let request_url = format!("http://ws.audioscrobbler.com/2.0/?method=user.gettoptracks&user={}&api_key={}&period=12month&limit=5&format=json", username, key);
-    let resp_text = reqwest::get(request_url)
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap();
-    let resp: Value = serde_json::from_str(&resp_text).unwrap();
-    let mut tracks = HashMap::with_capacity(5);
-    for t in resp["toptracks"]["track"].as_array().unwrap() {
-        let track_name = format!("{} - {}", t["artist"]["name"], t["name"]).replace("\"", "");
-        let playcount = t["playcount"]
-            .to_string()
-            .trim_matches('\"')
-            .parse::<i32>()
-            .unwrap_or(0);
-        tracks.insert(track_name, playcount);
-    }
-    tracks
-}
-
-// artists over 1 year
-pub async fn fetch_top_5_artists(username: &String) -> HashMap<String, i32> {
-    let key = get_api_key();
-    let request_url = format!("http://ws.audioscrobbler.com/2.0/?method=user.gettopartists&user={}&api_key={}&period=12month&limit=5&format=json", username, key);
-    let resp_text = reqwest::get(request_url)
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap();
-    let resp: Value = serde_json::from_str(&resp_text).unwrap();
-    let mut artists = HashMap::with_capacity(5);
-    for t in resp["topartists"]["artist"].as_array().unwrap() {
-        let artist = t["name"].as_str().unwrap().to_string();
-        let playcount = t["playcount"]
-            .to_string()
-            .trim_matches('\"')
-            .parse::<i32>()
-            .unwrap_or(0);
-        artists.insert(artist, playcount);
-    }
-    artists
-}
-
-pub async fn user_processable(username: &String) -> Result<(), UnprocessableErrors> {
-    let key = get_api_key();
-    let getinfo_url = format!(
-        "http://ws.audioscrobbler.com/2.0/?method=user.getinfo&user={}&api_key={}&format=json",
-        username, key
-    );
-    let getinfo_text = reqwest::get(getinfo_url)
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap();
-    let getinfo: Value = serde_json::from_str(&getinfo_text).unwrap();
-    if getinfo["error"] != Value::Null {
-        if getinfo["error"].as_i64().unwrap_or(0) == 6 {
-            return Err(UnprocessableErrors::UserNotFound);
+// LASTFM_REQUESTS_PER_SECOND, last.fm asks for no more than 5 a second per api key
+fn limiter() -> &'static RateLimiter {
+    static LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+    LIMITER.get_or_init(|| {
+        let rate = rate_from_env("LASTFM_REQUESTS_PER_SECOND", 5.0);
+        RateLimiter::new(rate, rate)
+    })
+}
+
+// one connection pool for every client
+fn shared_http() -> &'static reqwest::Client {
+    static HTTP: OnceLock<reqwest::Client> = OnceLock::new();
+    HTTP.get_or_init(reqwest::Client::new)
+}
+
+// the period parameter of user.getTopTracks and user.getTopArtists, periods end today
+#[derive(Clone, Copy)]
+pub enum Period {
+    Overall,
+    Week,
+    Month,
+    ThreeMonths,
+    SixMonths,
+    Year,
+}
+
+impl Period {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Period::Overall => "overall",
+            Period::Week => "7day",
+            Period::Month => "1month",
+            Period::ThreeMonths => "3month",
+            Period::SixMonths => "6month",
+            Period::Year => "12month",
         }
-    } else if getinfo["user"]["playcount"]
-        .as_str()
-        .unwrap()
-        .parse::<i64>()
+    }
+}
+
+pub struct TrackInfo {
+    pub artist: String,
+    pub title: String,
+    // 0 when last.fm doesnt know how long the track is
+    pub duration_ms: i64,
+}
+
+pub struct UserInfo {
+    pub name: String,
+    pub playcount: i64,
+    // None when it isnt set on the profile
+    pub country: Option<String>,
+    pub registered: i64,
+}
+
+#[derive(Clone)]
+pub struct LastFmClient {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: String,
+}
+
+impl LastFmClient {
+    pub fn new(base_url: &str, api_key: &str) -> Self {
+        Self {
+            http: shared_http().clone(),
+            base_url: base_url.to_string(),
+            api_key: api_key.to_string(),
+        }
+    }
+
+    // API_KEY is required, LASTFM_BASE_URL can point this at a mock server
+    pub fn from_env() -> Result<Self, WrappedErrors> {
+        let _ = dotenvy::dotenv();
+        let api_key = env::var("API_KEY").map_err(|_| {
+            WrappedErrors::Internal("missing last.fm API_KEY in the environment".to_string())
+        })?;
+        let base_url = env::var("LASTFM_BASE_URL").unwrap_or(DEFAULT_BASE_URL.to_string());
+        Ok(Self::new(&base_url, &api_key))
+    }
+
+    // Err has whether trying again might help, last.fm errors come as json with an error code
+    async fn call_once(
+        &self,
+        method: &str,
+        params: &[(&str, &str)],
+    ) -> Result<Value, (WrappedErrors, bool)> {
+        let resp = self
+            .http
+            .get(&self.base_url)
+            .query(&[
+                ("method", method),
+                ("api_key", &self.api_key),
+                ("format", "json"),
+            ])
+            .query(params)
+            .send()
+            .await
+            .map_err(|e| (WrappedErrors::Upstream(format!("last.fm: {}", e)), true))?;
+        let status = resp.status();
+        // a 429 from in front of the api doesnt always come with a json body
+        if status.as_u16() == 429 {
+            return Err((
+                WrappedErrors::RateLimited(format!("last.fm: {}", status)),
+                true,
+            ));
+        }
+        let body = match resp.json::<Value>().await {
+            Ok(body) => body,
+            Err(e) => {
+                return Err((
+                    WrappedErrors::Upstream(format!("last.fm: {}", e)),
+                    status.is_server_error(),
+                ))
+            }
+        };
+        let code = match body["error"].as_i64() {
+            None if status.is_success() => return Ok(body),
+            None => {
+                return Err((
+                    WrappedErrors::Upstream(format!("last.fm: {}", status)),
+                    status.is_server_error(),
+                ))
+            }
+            Some(code) => code,
+        };
+        let message = format!(
+            "last.fm: {}",
+            body["message"].as_str().unwrap_or("unknown error")
+        );
+        Err(match code {
+            // user or track not found
+            6 => (WrappedErrors::NotFound(message), false),
+            // login required, which is what a private profile looks like
+            17 => (UnprocessableErrors::PrivateProfile.into(), false),
+            29 => (WrappedErrors::RateLimited(message), true),
+            // operation failed, service offline, temporarily unavailable
+            8 | 11 | 16 => (WrappedErrors::Upstream(message), true),
+            _ => (WrappedErrors::Upstream(message), false),
+        })
+    }
+
+    async fn call(&self, method: &str, params: &[(&str, &str)]) -> Result<Value, WrappedErrors> {
+        let mut attempt = 0;
+        loop {
+            limiter().acquire().await;
+            attempt += 1;
+            let (e, retry) = match self.call_once(method, params).await {
+                Ok(body) => return Ok(body),
+                Err(e) => e,
+            };
+            if !retry || attempt >= MAX_ATTEMPTS {
+                return Err(e);
+            }
+            let wait = backoff(attempt);
+            if let WrappedErrors::RateLimited(_) = e {
+                limiter().pause(wait);
+            }
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    pub async fn track_info(&self, artist: &str, title: &str) -> Result<TrackInfo, WrappedErrors> {
+        let resp = self
+            .call("track.getInfo", &[("artist", artist), ("track", title)])
+            .await?;
+        let track = &resp["track"];
+        Ok(TrackInfo {
+            artist: track["artist"]["name"]
+                .as_str()
+                .unwrap_or(artist)
+                .to_string(),
+            title: track["name"].as_str().unwrap_or(title).to_string(),
+            duration_ms: number(&track["duration"]),
+        })
+    }
+
+    pub async fn user_info(&self, username: &str) -> Result<UserInfo, WrappedErrors> {
+        let resp = self.call("user.getInfo", &[("user", username)]).await?;
+        let user = &resp["user"];
+        Ok(UserInfo {
+            name: user["name"].as_str().unwrap_or(username).to_string(),
+            playcount: number(&user["playcount"]),
+            country: match user["country"].as_str() {
+                Some("None") | Some("") | None => None,
+                Some(c) => Some(c.to_string()),
+            },
+            registered: number(&user["registered"]["unixtime"]),
+        })
+    }
+
+    // "Artist - Title" -> playcount, same keys as Listen::track_name
+    pub async fn top_tracks(
+        &self,
+        username: &str,
+        period: Period,
+        limit: i64,
+    ) -> Result<HashMap<String, i32>, WrappedErrors> {
+        let limit = limit.to_string();
+        let resp = self
+            .call(
+                "user.getTopTracks",
+                &[
+                    ("user", username),
+                    ("period", period.as_str()),
+                    ("limit", &limit),
+                ],
+            )
+            .await?;
+        Ok(list(&resp["toptracks"]["track"])
+            .iter()
+            .map(|t| {
+                let name = format!(
+                    "{} - {}",
+                    t["artist"]["name"].as_str().unwrap_or(""),
+                    t["name"].as_str().unwrap_or("")
+                );
+                (name, number(&t["playcount"]) as i32)
+            })
+            .collect())
+    }
+
+    pub async fn top_artists(
+        &self,
+        username: &str,
+        period: Period,
+        limit: i64,
+    ) -> Result<HashMap<String, i32>, WrappedErrors> {
+        let limit = limit.to_string();
+        let resp = self
+            .call(
+                "user.getTopArtists",
+                &[
+                    ("user", username),
+                    ("period", period.as_str()),
+                    ("limit", &limit),
+                ],
+            )
+            .await?;
+        Ok(list(&resp["topartists"]["artist"])
+            .iter()
+            .map(|a| {
+                (
+                    a["name"].as_str().unwrap_or("").to_string(),
+                    number(&a["playcount"]) as i32,
+                )
+            })
+            .collect())
+    }
+
+    // tag -> count relative to the most used tag (100)
+    fn parse_tags(resp: &Value) -> Vec<(String, i64)> {
+        list(&resp["toptags"]["tag"])
+            .iter()
+            .filter_map(|t| Some((t["name"].as_str()?.to_string(), number(&t["count"]))))
+            .collect()
+    }
+
+    pub async fn artist_top_tags(&self, artist: &str) -> Result<Vec<(String, i64)>, WrappedErrors> {
+        let resp = self
+            .call(
+                "artist.getTopTags",
+                &[("artist", artist), ("autocorrect", "1")],
+            )
+            .await?;
+        Ok(Self::parse_tags(&resp))
+    }
+
+    pub async fn track_top_tags(
+        &self,
+        artist: &str,
+        title: &str,
+    ) -> Result<Vec<(String, i64)>, WrappedErrors> {
+        let resp = self
+            .call(
+                "track.getTopTags",
+                &[("artist", artist), ("track", title), ("autocorrect", "1")],
+            )
+            .await?;
+        Ok(Self::parse_tags(&resp))
+    }
+
+    // one page of scrobbles, newest first, and how many pages there are in total
+    pub async fn recent_tracks_page(
+        &self,
+        username: &str,
+        range: DateRange,
+        page: i64,
+        limit: i64,
+    ) -> Result<(Vec<Listen>, i64), WrappedErrors> {
+        let (from, to) = (range.from.to_string(), range.to.to_string());
+        let (page, limit) = (page.to_string(), limit.to_string());
+        let resp = self
+            .call(
+                "user.getRecentTracks",
+                &[
+                    ("user", username),
+                    ("from", &from),
+                    ("to", &to),
+                    ("page", &page),
+                    ("limit", &limit),
+                ],
+            )
+            .await?;
+        let recent = &resp["recenttracks"];
+        let listens = list(&recent["track"])
+            .iter()
+            .filter_map(parse_recent_track)
+            .filter(|l| l.timestamp >= range.from && l.timestamp < range.to)
+            .collect();
+        Ok((listens, number(&recent["@attr"]["totalPages"])))
+    }
+
+    // every scrobble in the range
+    pub async fn recent_tracks(
+        &self,
+        username: &str,
+        range: DateRange,
+    ) -> Result<Vec<Listen>, WrappedErrors> {
+        let mut listens = Vec::new();
+        let mut page = 1;
+        loop {
+            let (mut tracks, total_pages) = self
+                .recent_tracks_page(username, range, page, PAGE_SIZE)
+                .await?;
+            listens.append(&mut tracks);
+            if page >= total_pages {
+                break;
+            }
+            page += 1;
+        }
+        Ok(listens)
+    }
+}
+
+impl MetadataProvider for LastFmClient {
+    fn name(&self) -> &'static str {
+        "lastfm"
+    }
+
+    // last.fm says 0 for tracks it doesnt know the length of
+    fn duration<'a>(
+        &'a self,
+        track: TrackRef<'a>,
+    ) -> BoxFuture<'a, Result<Option<i64>, WrappedErrors>> {
+        Box::pin(async move {
+            match self.track_info(track.artist, track.title).await {
+                Ok(info) => Ok(Some(info.duration_ms).filter(|d| *d > 0)),
+                Err(WrappedErrors::NotFound(_)) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    // artist tags, or the tags of the track when nobody tagged the artist, weighted by count
+    fn genres<'a>(
+        &'a self,
+        artist: ArtistRef<'a>,
+    ) -> BoxFuture<'a, Result<Option<WeightedGenres>, WrappedErrors>> {
+        Box::pin(async move {
+            let mut tags = match self.artist_top_tags(artist.name).await {
+                Ok(tags) => tags,
+                Err(WrappedErrors::NotFound(_)) => Vec::new(),
+                Err(e) => return Err(e),
+            };
+            if tags.is_empty() {
+                if let Some(title) = artist.track_title {
+                    tags = match self.track_top_tags(artist.name, title).await {
+                        Ok(tags) => tags,
+                        Err(WrappedErrors::NotFound(_)) => Vec::new(),
+                        Err(e) => return Err(e),
+                    };
+                }
+            }
+            let tags = tags
+                .into_iter()
+                .filter(|(tag, count)| *count >= MIN_TAG_COUNT && genres::is_genre(tag))
+                .map(|(tag, count)| (tag, count as f64 / 100.0))
+                .collect::<Vec<_>>();
+            Ok((!tags.is_empty()).then_some(tags))
+        })
+    }
+}
+
+// last.fm sends numbers as strings, and sometimes as numbers
+fn number(v: &Value) -> i64 {
+    v.as_i64()
+        .or_else(|| v.as_str().and_then(|s| s.parse::<i64>().ok()))
         .unwrap_or(0)
-        < 365
-    {
-        return Err(UnprocessableErrors::NotEnoughScrobbles);
-    }
-
-    // cant check if users profile is private via the getinfo endpoint
-    let gettracks_url = format!(
-        "http://ws.audioscrobbler.com/2.0/?method=user.getrecenttracks&user={}&api_key={}&format=json",
-        username, key
-    );
-    let gettracks_text = reqwest::get(gettracks_url)
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap();
-    let gettracks: Value = serde_json::from_str(&gettracks_text).unwrap();
-    if gettracks["error"] != Value::Null && gettracks["error"].as_i64().unwrap_or(0) == 17 {
-        return Err(UnprocessableErrors::PrivateProfile);
+}
+
+// a list with one entry comes back as just that entry
+fn list(v: &Value) -> Vec<Value> {
+    match v {
+        Value::Array(a) => a.clone(),
+        Value::Object(_) => vec![v.clone()],
+        _ => Vec::new(),
+    }
+}
+
+// the track playing right now has no date yet and gets skipped
+fn parse_recent_track(t: &Value) -> Option<Listen> {
+    if t["@attr"]["nowplaying"].as_str() == Some("true") {
+        return None;
+    }
+    Some(Listen {
+        artist: t["artist"]["#text"].as_str()?.to_string(),
+        title: t["name"].as_str()?.to_string(),
+        timestamp: t["date"]["uts"].as_str()?.parse().ok()?,
+        played_ms: None,
+        mbid: listens::mbid(t["mbid"].as_str()),
+    })
+}
+
+// country set on the users profile
+pub async fn fetch_user_country(username: &str) -> Option<String> {
+    let client = LastFmClient::from_env().ok()?;
+    client.user_info(username).await.ok()?.country
+}
+
+pub async fn user_processable(username: &str) -> Result<(), WrappedErrors> {
+    let client = LastFmClient::from_env()?;
+    let info = match client.user_info(username).await {
+        Ok(info) => info,
+        Err(WrappedErrors::NotFound(_)) => return Err(UnprocessableErrors::UserNotFound.into()),
+        Err(e) => return Err(e),
+    };
+    if info.playcount < 365 {
+        return Err(UnprocessableErrors::NotEnoughScrobbles.into());
     }
 
+    // cant check if users profile is private via the getinfo endpoint, getrecenttracks fails with
+    // PrivateProfile for those
+    client
+        .call(
+            "user.getRecentTracks",
+            &[("user", username), ("limit", "1")],
+        )
+        .await?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn track_info(duration: &str) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({
+            "track": {"name": "Song", "artist": {"name": "Artist"}, "duration": duration}
+        }))
+    }
+
+    // the same path LASTFM_BASE_URL points at in production
+    fn client(server: &MockServer) -> LastFmClient {
+        LastFmClient::new(&format!("{}/2.0/", server.uri()), "key")
+    }
+
+    async fn mock_track_info(server: &MockServer, first: ResponseTemplate) {
+        Mock::given(method("GET"))
+            .and(path("/2.0/"))
+            .and(query_param("method", "track.getInfo"))
+            .respond_with(first)
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/2.0/"))
+            .and(query_param("method", "track.getInfo"))
+            .respond_with(track_info("180000"))
+            .expect(1)
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn retries_after_too_many_requests() {
+        let server = MockServer::start().await;
+        mock_track_info(
+            &server,
+            ResponseTemplate::new(429).set_body_string("slow down"),
+        )
+        .await;
+        let info = client(&server).track_info("Artist", "Song").await;
+        assert_eq!(info.unwrap().duration_ms, 180000);
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors() {
+        let server = MockServer::start().await;
+        mock_track_info(&server, ResponseTemplate::new(503).set_body_string("down")).await;
+        let info = client(&server).track_info("Artist", "Song").await;
+        assert_eq!(info.unwrap().duration_ms, 180000);
+    }
+
+    #[tokio::test]
+    async fn retries_rate_limit_exceeded() {
+        let server = MockServer::start().await;
+        let limited = ResponseTemplate::new(200)
+            .set_body_json(json!({"error": 29, "message": "Rate Limit Exceeded"}));
+        mock_track_info(&server, limited).await;
+        let info = client(&server).track_info("Artist", "Song").await;
+        assert_eq!(info.unwrap().duration_ms, 180000);
+    }
+
+    #[tokio::test]
+    async fn not_found_isnt_retried() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/2.0/"))
+            .respond_with(
+                ResponseTemplate::new(404)
+                    .set_body_json(json!({"error": 6, "message": "Track not found"})),
+            )
+            // once per call below
+            .expect(2)
+            .mount(&server)
+            .await;
+        let client = client(&server);
+        let info = client.track_info("Artist", "Song").await;
+        assert!(matches!(info, Err(WrappedErrors::NotFound(_))));
+        // the metadata chain treats not found as nothing known rather than a failure
+        let duration = client
+            .duration(TrackRef {
+                artist: "Artist",
+                title: "Song",
+                mbid: None,
+                duration_ms: None,
+            })
+            .await;
+        assert!(matches!(duration, Ok(None)));
+    }
+}
@@ -0,0 +1,132 @@
+use crate::listens::{self, Listen};
+use chrono::NaiveDateTime;
+use serde_json::Value;
+use std::error::Error;
+
+// lastfm-to-csv writes dates like "31 Jan 2024 12:34" in utc
+const CSV_DATE_FORMAT: &str = "%d %b %Y %H:%M";
+
+fn parse_date(date: &str) -> Option<i64> {
+    let date = date.trim();
+    if let Ok(uts) = date.parse::<i64>() {
+        return Some(uts);
+    }
+    NaiveDateTime::parse_from_str(date, CSV_DATE_FORMAT)
+        .ok()
+        .map(|d| d.and_utc().timestamp())
+}
+
+// artist,album,track,date rows without a header, rows with an unreadable date are skipped
+// which also takes care of a header row if there is one
+pub fn parse_csv(text: &str) -> Result<Vec<Listen>, Box<dyn Error>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(text.as_bytes());
+    let mut listens = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        if record.len() < 4 {
+            continue;
+        }
+        if let Some(timestamp) = parse_date(&record[3]) {
+            listens.push(Listen {
+                artist: record[0].to_string(),
+                title: record[2].to_string(),
+                timestamp,
+                played_ms: None,
+                mbid: None,
+            });
+        }
+    }
+    Ok(listens)
+}
+
+// either a flat array of tracks or an array of user.getrecenttracks pages,
+// tracks can be flat ({artist, track, timestamp}) or shaped like the api response
+pub fn parse_json(text: &str) -> Result<Vec<Listen>, Box<dyn Error>> {
+    let export: Value = serde_json::from_str(text)?;
+    let entries = export.as_array().ok_or("expected a json array")?;
+    let tracks = entries.iter().flat_map(|e| {
+        let page = if e["recenttracks"].is_object() {
+            &e["recenttracks"]["track"]
+        } else {
+            &e["track"]
+        };
+        match page.as_array() {
+            Some(p) => p.iter().collect::<Vec<&Value>>(),
+            None => vec![e],
+        }
+    });
+    Ok(tracks.filter_map(parse_json_track).collect())
+}
+
+fn parse_json_track(t: &Value) -> Option<Listen> {
+    let artist = t["artist"]["#text"]
+        .as_str()
+        .or(t["artist"]["name"].as_str())
+        .or(t["artist"].as_str())?;
+    let title = t["name"].as_str().or(t["track"].as_str())?;
+    // tracks without a date are the currently playing one
+    let timestamp = match &t["date"] {
+        Value::Object(d) => parse_date(d.get("uts")?.as_str()?),
+        Value::String(d) => parse_date(d),
+        _ => t["timestamp"]
+            .as_i64()
+            .or(t["timestamp"].as_str().and_then(parse_date)),
+    }?;
+    Some(Listen {
+        artist: artist.to_string(),
+        title: title.to_string(),
+        timestamp,
+        played_ms: None,
+        mbid: listens::mbid(t["mbid"].as_str()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_csv_as_artist_album_track_date() {
+        let csv = "artist,album,track,date\n\
+                   Artist,Album,Song,31 Jan 2024 12:34\n\
+                   \"Comma, Artist\",Album,Other Song,1706704440\n\
+                   Artist,Album,No Date\n\
+                   Artist,Album,Bad Date,sometime\n";
+        let listens = parse_csv(csv).unwrap();
+        assert_eq!(listens.len(), 2);
+        assert_eq!(listens[0].artist, "Artist");
+        assert_eq!(listens[0].title, "Song");
+        assert_eq!(listens[0].timestamp, 1_706_704_440);
+        assert_eq!(listens[1].artist, "Comma, Artist");
+        assert_eq!(listens[1].title, "Other Song");
+        assert_eq!(listens[1].timestamp, 1_706_704_440);
+    }
+
+    #[test]
+    fn reads_json_pages_and_flat_tracks() {
+        let pages = r##"[{"recenttracks": {"track": [
+            {"artist": {"#text": "Artist"}, "name": "Song", "mbid": "",
+             "date": {"uts": "1706704440"}},
+            {"artist": {"#text": "Artist"}, "name": "Now Playing"}
+        ]}}]"##;
+        let listens = parse_json(pages).unwrap();
+        assert_eq!(listens.len(), 1);
+        assert_eq!(listens[0].title, "Song");
+        assert_eq!(listens[0].timestamp, 1_706_704_440);
+        assert_eq!(listens[0].mbid, None);
+
+        let flat = r#"[{"artist": "Artist", "track": "Song", "timestamp": 1706704440}]"#;
+        let listens = parse_json(flat).unwrap();
+        assert_eq!(listens.len(), 1);
+        assert_eq!(listens[0].artist, "Artist");
+    }
+
+    #[test]
+    fn rejects_json_that_isnt_an_array() {
+        assert!(parse_json("{}").is_err());
+        assert!(parse_json("not json").is_err());
+    }
+}
@@ -0,0 +1,311 @@
+use crate::{
+    daterange::DateRange,
+    listens::{self, Listen},
+};
+use dotenvy;
+use reqwest::{RequestBuilder, StatusCode};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    error::Error,
+};
+
+const DEFAULT_BASE_URL: &str = "https://api.listenbrainz.org";
+// most listens the api hands out per request
+const PAGE_SIZE: i64 = 1000;
+
+#[derive(Clone)]
+pub struct ListenBrainzClient {
+    http: reqwest::Client,
+    base_url: String,
+    username: String,
+    token: Option<String>,
+}
+
+impl ListenBrainzClient {
+    pub fn new(base_url: &str, username: &str, token: Option<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            username: username.to_string(),
+            token,
+        }
+    }
+
+    // LISTENBRAINZ_BASE_URL can point this at a mock server, the token is optional
+    pub fn from_env(username: &str) -> Self {
+        let _ = dotenvy::dotenv();
+        let base_url = env::var("LISTENBRAINZ_BASE_URL").unwrap_or(DEFAULT_BASE_URL.to_string());
+        Self::new(&base_url, username, env::var("LISTENBRAINZ_TOKEN").ok())
+    }
+
+    // path is everything after /1/
+    fn get(&self, path: &str) -> RequestBuilder {
+        let request = self.http.get(format!("{}/1/{}", self.base_url, path));
+        match &self.token {
+            Some(token) => request.header("Authorization", format!("Token {}", token)),
+            None => request,
+        }
+    }
+
+    // null when there is nothing yet, stats answer 204 until they have been calculated
+    async fn get_json(&self, request: RequestBuilder) -> Result<Value, Box<dyn Error>> {
+        let resp = request.send().await?.error_for_status()?;
+        if resp.status() == StatusCode::NO_CONTENT {
+            return Ok(Value::Null);
+        }
+        Ok(resp.json::<Value>().await?)
+    }
+
+    // listens come newest first, so this pages backwards from the end of the range.
+    // max_ts is exclusive and several listens can share a second, so each page starts one second
+    // after the oldest listen of the last one and the overlap is thrown away
+    pub async fn listens(&self, range: DateRange) -> Result<Vec<Listen>, Box<dyn Error>> {
+        let mut listens = Vec::new();
+        let mut seen = HashSet::new();
+        let mut max_ts = range.to;
+        loop {
+            let request = self
+                .get(&format!("user/{}/listens", self.username))
+                .query(&[("max_ts", max_ts), ("count", PAGE_SIZE)]);
+            let resp = self.get_json(request).await?;
+            let page = match resp["payload"]["listens"].as_array() {
+                Some(p) if !p.is_empty() => p.clone(),
+                _ => break,
+            };
+            let mut oldest = max_ts;
+            for l in &page {
+                let listened_at = l["listened_at"].as_i64().unwrap_or(0);
+                oldest = oldest.min(listened_at);
+                if listened_at < range.from || !seen.insert(listen_key(l)) {
+                    continue;
+                }
+                if let Some(listen) = parse_listen(l) {
+                    listens.push(listen);
+                }
+            }
+            if oldest < range.from || (page.len() as i64) < PAGE_SIZE {
+                break;
+            }
+            // a whole page inside one second would ask for the same page forever
+            max_ts = if oldest + 1 < max_ts {
+                oldest + 1
+            } else {
+                oldest
+            };
+        }
+        Ok(listens)
+    }
+
+    // stats are only precomputed for fixed ranges like this_year and year (last calendar year)
+    async fn stats(
+        &self,
+        entity: &str,
+        stats_range: &str,
+    ) -> Result<Vec<(String, i32)>, Box<dyn Error>> {
+        let request = self
+            .get(&format!("stats/user/{}/{}", self.username, entity))
+            .query(&[("range", stats_range), ("count", "5")]);
+        let resp = self.get_json(request).await?;
+        let entries = resp["payload"][entity]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        Ok(entries
+            .iter()
+            .map(|e| {
+                let name = match entity {
+                    "recordings" => format!(
+                        "{} - {}",
+                        e["artist_name"].as_str().unwrap_or(""),
+                        e["track_name"].as_str().unwrap_or("")
+                    ),
+                    _ => e["artist_name"].as_str().unwrap_or("").to_string(),
+                };
+                (name, e["listen_count"].as_i64().unwrap_or(0) as i32)
+            })
+            .collect())
+    }
+
+    pub async fn top_5_tracks(
+        &self,
+        stats_range: &str,
+    ) -> Result<HashMap<String, i32>, Box<dyn Error>> {
+        Ok(self
+            .stats("recordings", stats_range)
+            .await?
+            .into_iter()
+            .collect())
+    }
+
+    pub async fn top_5_artists(
+        &self,
+        stats_range: &str,
+    ) -> Result<HashMap<String, i32>, Box<dyn Error>> {
+        Ok(self
+            .stats("artists", stats_range)
+            .await?
+            .into_iter()
+            .collect())
+    }
+}
+
+// listens dont have an id, the submission msid plus the time is as close as it gets
+fn listen_key(l: &Value) -> (i64, String) {
+    let metadata = &l["track_metadata"];
+    let id = l["recording_msid"]
+        .as_str()
+        .or(metadata["additional_info"]["recording_msid"].as_str())
+        .map(String::from)
+        .unwrap_or_else(|| {
+            format!(
+                "{}\t{}",
+                metadata["artist_name"].as_str().unwrap_or(""),
+                metadata["track_name"].as_str().unwrap_or("")
+            )
+        });
+    (l["listened_at"].as_i64().unwrap_or(0), id)
+}
+
+// duration_ms is the length of the track, which is the same estimate last.fm lookups give us
+fn parse_listen(l: &Value) -> Option<Listen> {
+    let metadata = &l["track_metadata"];
+    let info = &metadata["additional_info"];
+    let played_ms = info["duration_ms"]
+        .as_i64()
+        .or(info["duration"].as_i64().map(|s| s * 1000));
+    Some(Listen {
+        artist: metadata["artist_name"].as_str()?.to_string(),
+        title: metadata["track_name"].as_str()?.to_string(),
+        timestamp: l["listened_at"].as_i64()?,
+        played_ms,
+        mbid: listens::mbid(
+            info["recording_mbid"]
+                .as_str()
+                .or(metadata["mbid_mapping"]["recording_mbid"].as_str()),
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Tz;
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn listen(listened_at: i64, n: i64) -> Value {
+        json!({
+            "listened_at": listened_at,
+            "recording_msid": format!("msid-{}", n),
+            "track_metadata": {"artist_name": "Artist", "track_name": format!("Song {}", n)},
+        })
+    }
+
+    fn page(listens: Vec<Value>) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({"payload": {"listens": listens}}))
+    }
+
+    #[tokio::test]
+    async fn pages_without_losing_listens_that_share_a_second() {
+        let server = MockServer::start().await;
+        // two listens a second, the last two of the full page are at 99_500
+        let first = (0..PAGE_SIZE).map(|i| listen(99_999 - i / 2, i)).collect();
+        // the next page starts at 99_501 so both come back along with one that didnt fit
+        let second = vec![
+            listen(99_500, PAGE_SIZE - 2),
+            listen(99_500, PAGE_SIZE - 1),
+            listen(99_500, PAGE_SIZE),
+            listen(99_499, PAGE_SIZE + 1),
+        ];
+        Mock::given(method("GET"))
+            .and(path("/1/user/someone/listens"))
+            .and(query_param("max_ts", "100000"))
+            .respond_with(page(first))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/1/user/someone/listens"))
+            .and(query_param("max_ts", "99501"))
+            .respond_with(page(second))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = ListenBrainzClient::new(&server.uri(), "someone", None);
+        let range = DateRange::new(1, 100_000, Tz::UTC).unwrap();
+        let listens = client.listens(range).await.unwrap();
+        assert_eq!(listens.len() as i64, PAGE_SIZE + 2);
+        let titles = listens
+            .iter()
+            .map(|l| l.title.as_str())
+            .collect::<HashSet<_>>();
+        assert_eq!(titles.len(), listens.len());
+        assert!(titles.contains(format!("Song {}", PAGE_SIZE).as_str()));
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_start_of_the_range() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/1/user/someone/listens"))
+            .respond_with(page(vec![listen(150, 1), listen(120, 2), listen(90, 3)]))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = ListenBrainzClient::new(&server.uri(), "someone", None);
+        let range = DateRange::new(100, 200, Tz::UTC).unwrap();
+        let listens = client.listens(range).await.unwrap();
+        assert_eq!(listens.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn reads_top_stats() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/1/stats/user/someone/recordings"))
+            .and(query_param("range", "this_year"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "payload": {"recordings": [
+                    {"artist_name": "Artist", "track_name": "Song", "listen_count": 12},
+                    {"artist_name": "Other", "track_name": "Tune", "listen_count": 3},
+                ]}
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/1/stats/user/someone/artists"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "payload": {"artists": [{"artist_name": "Artist", "listen_count": 15}]}
+            })))
+            .mount(&server)
+            .await;
+
+        let client = ListenBrainzClient::new(&server.uri(), "someone", None);
+        let tracks = client.top_5_tracks("this_year").await.unwrap();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks["Artist - Song"], 12);
+        let artists = client.top_5_artists("this_year").await.unwrap();
+        assert_eq!(artists["Artist"], 15);
+    }
+
+    #[tokio::test]
+    async fn stats_not_calculated_yet_are_empty() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/1/stats/user/someone/artists"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+
+        let client = ListenBrainzClient::new(&server.uri(), "someone", None);
+        let artists = client.top_5_artists("this_year").await.unwrap();
+        assert!(artists.is_empty());
+    }
+}
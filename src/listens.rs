@@ -0,0 +1,36 @@
+use crate::metadata::TrackRef;
+use serde::{Deserialize, Serialize};
+
+// a single play, no matter which service it came from
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Listen {
+    pub artist: String,
+    pub title: String,
+    // unix timestamp of when the play started
+    pub timestamp: i64,
+    // how long it was actually played for, None when it has to be looked up
+    pub played_ms: Option<i64>,
+    // musicbrainz recording id, when the service knew it
+    #[serde(default)]
+    pub mbid: Option<String>,
+}
+
+impl Listen {
+    pub fn track_name(&self) -> String {
+        format!("{} - {}", self.artist, self.title)
+    }
+
+    pub fn track_ref(&self) -> TrackRef<'_> {
+        TrackRef {
+            artist: &self.artist,
+            title: &self.title,
+            mbid: self.mbid.as_deref(),
+            duration_ms: None,
+        }
+    }
+}
+
+// services send an empty string rather than leaving the mbid out
+pub fn mbid(id: Option<&str>) -> Option<String> {
+    id.filter(|id| !id.is_empty()).map(String::from)
+}
@@ -0,0 +1,364 @@
+use crate::{daterange::DateRange, error::WrappedErrors, lfm::LastFmClient, listens::Listen};
+use chrono::Utc;
+use dotenvy;
+use rusqlite::{params, Connection};
+use std::{env, error::Error, time::Duration};
+use tokio::task;
+
+// last.fm takes scrobbles up to two weeks after they happened (offline players, scrobbling apps
+// catching up), so the last two weeks before a sync arent final yet
+const LATE_SCROBBLE_WINDOW: i64 = 14 * 24 * 60 * 60;
+// a sync this recent is trusted as it is, otherwise every request would fetch the last two weeks
+const RESYNC_AFTER: i64 = 10 * 60;
+
+// listens are kept apart per source so an import never mixes with synced scrobbles
+#[derive(Clone, Copy)]
+pub enum StoredSource {
+    LastFm,
+    Import,
+}
+
+impl StoredSource {
+    fn as_str(self) -> &'static str {
+        match self {
+            StoredSource::LastFm => "lastfm",
+            StoredSource::Import => "import",
+        }
+    }
+}
+
+fn db_path() -> String {
+    let _ = dotenvy::dotenv();
+    env::var("LISTEN_DB").unwrap_or("listens.db".to_string())
+}
+
+fn open() -> rusqlite::Result<Connection> {
+    let conn = Connection::open(db_path())?;
+    // rocket handles requests concurrently, so writers wait on each other instead of failing
+    conn.busy_timeout(Duration::from_secs(10))?;
+    create_tables(&conn)?;
+    Ok(conn)
+}
+
+// rusqlite blocks the thread it runs on, so the store is opened and queried on tokio's
+// blocking pool instead of holding up a worker
+async fn blocking<T: Send + 'static>(
+    query: impl FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+) -> Result<T, WrappedErrors> {
+    Ok(task::spawn_blocking(move || query(&mut open()?)).await??)
+}
+
+fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS listens (
+            username TEXT NOT NULL,
+            source TEXT NOT NULL,
+            timestamp INTEGER NOT NULL,
+            artist TEXT NOT NULL,
+            title TEXT NOT NULL,
+            played_ms INTEGER,
+            mbid TEXT,
+            PRIMARY KEY (username, source, timestamp, artist, title)
+        );
+        CREATE TABLE IF NOT EXISTS syncs (
+            username TEXT NOT NULL,
+            source TEXT NOT NULL,
+            synced_from INTEGER NOT NULL,
+            synced_to INTEGER NOT NULL,
+            synced_at INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS syncs_by_user ON syncs (username, source);",
+    )
+}
+
+fn insert_listens(
+    conn: &mut Connection,
+    username: &str,
+    source: StoredSource,
+    listens: &[Listen],
+) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut insert = tx.prepare(
+            "INSERT OR IGNORE INTO listens
+            (username, source, timestamp, artist, title, played_ms, mbid)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?;
+        for l in listens {
+            insert.execute(params![
+                username.to_lowercase(),
+                source.as_str(),
+                l.timestamp,
+                l.artist,
+                l.title,
+                l.played_ms,
+                l.mbid
+            ])?;
+        }
+    }
+    tx.commit()
+}
+
+// the same play stored twice only counts once, returns how many listens the user has stored now
+pub async fn save_listens(
+    username: &str,
+    source: StoredSource,
+    listens: Vec<Listen>,
+) -> Result<i64, WrappedErrors> {
+    let username = username.to_string();
+    blocking(move |conn| {
+        insert_listens(conn, &username, source, &listens)?;
+        conn.query_row(
+            "SELECT COUNT(*) FROM listens WHERE username = ?1 AND source = ?2",
+            params![username.to_lowercase(), source.as_str()],
+            |r| r.get(0),
+        )
+    })
+    .await
+}
+
+pub async fn load_listens(
+    username: &str,
+    source: StoredSource,
+    range: DateRange,
+) -> Result<Vec<Listen>, WrappedErrors> {
+    let username = username.to_lowercase();
+    blocking(move |conn| {
+        let mut select = conn.prepare(
+            "SELECT artist, title, timestamp, played_ms, mbid FROM listens
+            WHERE username = ?1 AND source = ?2 AND timestamp >= ?3 AND timestamp < ?4
+            ORDER BY timestamp",
+        )?;
+        let listens = select
+            .query_map(
+                params![username, source.as_str(), range.from, range.to],
+                |r| {
+                    Ok(Listen {
+                        artist: r.get(0)?,
+                        title: r.get(1)?,
+                        timestamp: r.get(2)?,
+                        played_ms: r.get(3)?,
+                        mbid: r.get(4)?,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<Listen>>>()?;
+        Ok(listens)
+    })
+    .await
+}
+
+// one window of scrobbles that was fetched completely at synced_at
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct SyncedSpan {
+    from: i64,
+    to: i64,
+    synced_at: i64,
+}
+
+impl SyncedSpan {
+    // the part nothing can be added to anymore, or all of it when it was only just fetched.
+    // a span that had reached the present back then still counts as reaching it
+    fn settled_to(&self, now: i64) -> i64 {
+        if now - self.synced_at < RESYNC_AFTER {
+            if self.to >= self.synced_at {
+                now
+            } else {
+                self.to
+            }
+        } else {
+            self.to.min(self.synced_at - LATE_SCROBBLE_WINDOW)
+        }
+    }
+}
+
+// the bits of from..to no span has settled yet, oldest first
+fn missing_spans(from: i64, to: i64, spans: &[SyncedSpan], now: i64) -> Vec<(i64, i64)> {
+    let mut settled = spans
+        .iter()
+        .map(|s| (s.from, s.settled_to(now)))
+        .filter(|(f, t)| f < t)
+        .collect::<Vec<_>>();
+    settled.sort();
+    let mut missing = Vec::new();
+    let mut cursor = from;
+    for (f, t) in settled {
+        if f >= to {
+            break;
+        }
+        if f > cursor {
+            missing.push((cursor, f));
+        }
+        cursor = cursor.max(t);
+    }
+    if cursor < to {
+        missing.push((cursor, to));
+    }
+    missing
+}
+
+fn synced_spans(
+    conn: &Connection,
+    username: &str,
+    source: StoredSource,
+) -> rusqlite::Result<Vec<SyncedSpan>> {
+    let mut select = conn.prepare(
+        "SELECT synced_from, synced_to, synced_at FROM syncs
+        WHERE username = ?1 AND source = ?2",
+    )?;
+    let spans = select
+        .query_map(params![username.to_lowercase(), source.as_str()], |r| {
+            Ok(SyncedSpan {
+                from: r.get(0)?,
+                to: r.get(1)?,
+                synced_at: r.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<SyncedSpan>>>()?;
+    Ok(spans)
+}
+
+// spans dont overlap, whatever older spans had of the new one is cut out of them
+fn add_synced_span(
+    conn: &mut Connection,
+    username: &str,
+    source: StoredSource,
+    span: SyncedSpan,
+) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    let (username, source) = (username.to_lowercase(), source.as_str());
+    // the pieces of overlapping spans that stick out on either side are kept
+    tx.execute(
+        "INSERT INTO syncs (username, source, synced_from, synced_to, synced_at)
+        SELECT username, source, synced_from, ?3, synced_at FROM syncs
+        WHERE username = ?1 AND source = ?2 AND synced_from < ?3 AND synced_to > ?3
+        UNION ALL
+        SELECT username, source, ?4, synced_to, synced_at FROM syncs
+        WHERE username = ?1 AND source = ?2 AND synced_from < ?4 AND synced_to > ?4",
+        params![username, source, span.from, span.to],
+    )?;
+    tx.execute(
+        "DELETE FROM syncs WHERE username = ?1 AND source = ?2
+        AND synced_from < ?4 AND synced_to > ?3",
+        params![username, source, span.from, span.to],
+    )?;
+    tx.execute(
+        "INSERT INTO syncs (username, source, synced_from, synced_to, synced_at)
+        VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![username, source, span.from, span.to, span.synced_at],
+    )?;
+    tx.commit()
+}
+
+// only the part of range that isnt in the store yet gets fetched. scrobbles from the two weeks
+// before a sync can still change, so those are fetched again once that sync is a few minutes old
+// and a request asks for them. a span is only recorded once its fetch went through, a failed
+// page would otherwise leave a hole nothing fetches again
+pub async fn sync_lastfm(username: &str, range: DateRange) -> Result<(), Box<dyn Error>> {
+    let now = Utc::now().timestamp();
+    let spans = {
+        let username = username.to_string();
+        blocking(move |conn| synced_spans(conn, &username, StoredSource::LastFm)).await?
+    };
+    let missing = missing_spans(range.from, range.to.min(now), &spans, now);
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let client = LastFmClient::from_env()?;
+    for (from, to) in missing {
+        let window = DateRange {
+            from,
+            to,
+            tz: range.tz,
+        };
+        let listens = client.recent_tracks(username, window).await?;
+        // listens already stored are ignored when saving
+        save_listens(username, StoredSource::LastFm, listens).await?;
+        let span = SyncedSpan {
+            from,
+            to,
+            synced_at: now,
+        };
+        let username = username.to_string();
+        blocking(move |conn| add_synced_span(conn, &username, StoredSource::LastFm, span)).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 24 * 60 * 60;
+    const NOW: i64 = 1000 * DAY;
+
+    fn span(from: i64, to: i64, synced_at: i64) -> SyncedSpan {
+        SyncedSpan {
+            from,
+            to,
+            synced_at,
+        }
+    }
+
+    #[test]
+    fn first_sync_fetches_only_the_range() {
+        assert_eq!(
+            missing_spans(10 * DAY, 20 * DAY, &[], NOW),
+            vec![(10 * DAY, 20 * DAY)]
+        );
+    }
+
+    #[test]
+    fn old_ranges_next_to_a_synced_one_fetch_only_the_gap() {
+        let spans = [span(500 * DAY, NOW, NOW - DAY)];
+        // the last two weeks before the sync a day ago arent settled
+        assert_eq!(
+            missing_spans(400 * DAY, NOW, &spans, NOW),
+            vec![(400 * DAY, 500 * DAY), (NOW - 15 * DAY, NOW)]
+        );
+        assert!(missing_spans(600 * DAY, 700 * DAY, &spans, NOW).is_empty());
+    }
+
+    #[test]
+    fn a_recent_sync_isnt_fetched_again() {
+        let spans = [span(500 * DAY, NOW - 60, NOW - 60)];
+        assert!(missing_spans(500 * DAY, NOW, &spans, NOW).is_empty());
+    }
+
+    #[test]
+    fn new_spans_are_cut_out_of_old_ones() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        let add = |conn: &mut Connection, s| {
+            add_synced_span(conn, "Someone", StoredSource::LastFm, s).unwrap()
+        };
+        add(&mut conn, span(10 * DAY, 40 * DAY, DAY));
+        add(&mut conn, span(20 * DAY, 30 * DAY, 2 * DAY));
+        add(&mut conn, span(35 * DAY, 50 * DAY, 3 * DAY));
+        let mut spans = synced_spans(&conn, "someone", StoredSource::LastFm).unwrap();
+        spans.sort_by_key(|s| s.from);
+        assert_eq!(
+            spans,
+            vec![
+                span(10 * DAY, 20 * DAY, DAY),
+                span(20 * DAY, 30 * DAY, 2 * DAY),
+                span(30 * DAY, 35 * DAY, DAY),
+                span(35 * DAY, 50 * DAY, 3 * DAY),
+            ]
+        );
+    }
+
+    #[test]
+    fn spans_with_holes_fetch_every_hole() {
+        let spans = [
+            span(10 * DAY, 20 * DAY, NOW),
+            span(30 * DAY, 40 * DAY, NOW),
+            span(15 * DAY, 25 * DAY, NOW),
+        ];
+        assert_eq!(
+            missing_spans(0, 50 * DAY, &spans, NOW),
+            vec![(0, 10 * DAY), (25 * DAY, 30 * DAY), (40 * DAY, 50 * DAY)]
+        );
+    }
+}
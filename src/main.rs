@@ -1,23 +1,84 @@
 pub mod api;
+pub mod cache;
+pub mod cacheadmin;
 pub mod calculations;
+pub mod cli;
+pub mod coverartarchive;
+pub mod daterange;
 pub mod defaults;
+pub mod error;
+pub mod genres;
 pub mod imageprocessing;
+pub mod imageresponse;
+pub mod jobs;
 pub mod lfm;
+pub mod lfmexport;
+pub mod listenbrainz;
+pub mod listens;
+pub mod listenstore;
+pub mod matching;
+pub mod metadata;
+pub mod musicbrainz;
+pub mod ratelimit;
+pub mod scrobblerlog;
+pub mod source;
 pub mod spotify;
+pub mod spotifyhistory;
+pub mod stats;
+pub mod taxonomy;
 #[macro_use]
 extern crate rocket;
 
-#[launch]
-fn rocket() -> _ {
-    rocket::build().mount(
-        "/",
-        routes![
-            api::minutes_listened,
-            api::top_song,
-            api::top_5_songs,
-            api::genre_evolution,
-            api::final_image,
-            api::user_processable
-        ],
-    )
+use rocket::{Build, Rocket};
+use std::{env, process};
+
+fn rocket() -> Rocket<Build> {
+    rocket::build()
+        .manage(jobs::Jobs::default())
+        .manage(spotify::SharedSpotify::from_env())
+        .mount(
+            "/",
+            routes![
+                api::minutes_listened,
+                api::top_song,
+                api::top_5_songs,
+                api::genre_evolution,
+                api::wrapped,
+                api::stats,
+                api::final_image,
+                api::minutes_listened_spotify_history,
+                api::import_listens,
+                api::user_processable,
+                api::start_job,
+                api::job_status,
+                api::job_events,
+                api::user_progress,
+                api::job_result,
+                cacheadmin::list_entries,
+                cacheadmin::lookup_entry,
+                cacheadmin::override_entry,
+                cacheadmin::delete_entry,
+                cacheadmin::export_entries,
+                cacheadmin::cache_stats
+            ],
+        )
+        .register("/", catchers![error::default_catcher])
+}
+
+// without arguments this runs the server, `lastfmwrapped cache ...` manages the metadata cache
+#[rocket::main]
+async fn main() -> Result<(), Box<rocket::Error>> {
+    let args = env::args().collect::<Vec<String>>();
+    if args.get(1).map(String::as_str) == Some("cache") {
+        // the server logs through rocket, the cli has nothing else printing warnings
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+        if let Err(e) = cli::cache_command(&args[2..]) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let _ = rocket().launch().await?;
+    Ok(())
 }
@@ -0,0 +1,198 @@
+use regex::Regex;
+use std::sync::OnceLock;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+// best candidates under this are treated as no match at all
+pub const TRACK_THRESHOLD: f64 = 0.85;
+pub const ARTIST_THRESHOLD: f64 = 0.85;
+// right artist wrong song scores well overall, the title has to be close on its own too
+const MIN_TITLE_SIMILARITY: f64 = 0.8;
+// durations further apart than this count as a different recording
+const DURATION_TOLERANCE_MS: f64 = 30.0 * 1000.0;
+
+// bracketed bits that dont change which song it is, "(feat. X)", "[Remastered 2011]", "(Live)"
+fn bracket_noise() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(r"\s*[\(\[][^\)\]]*\b(feat|ft|featuring|with|remaster|remastered|live|version|edit|mono|stereo|deluxe|bonus|demo|acoustic)\b[^\)\]]*[\)\]]").unwrap()
+    })
+}
+
+// the same after a dash, "Song - Remastered 2011", "Song - Live at Wembley"
+fn dash_noise() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(r"\s+-\s+.*\b(remaster|remastered|live|version|edit|mono|stereo|mix|demo|acoustic)\b.*$").unwrap()
+    })
+}
+
+// "Artist feat. X", "Song ft. X"
+fn featuring() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"\s+(feat\.?|ft\.?|featuring)\s+.*$").unwrap())
+}
+
+// lowercase without diacritics, punctuation, featured artists and remaster/live/edit tags
+pub fn normalize(s: &str) -> String {
+    let folded = s
+        .nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .collect::<String>()
+        .to_lowercase();
+    let stripped = bracket_noise().replace_all(&folded, "");
+    let stripped = dash_noise().replace(&stripped, "");
+    let stripped = featuring().replace(&stripped, "");
+    stripped
+        .replace('&', " and ")
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn levenshtein(a: &[char], b: &[char]) -> usize {
+    let mut row = (0..=b.len()).collect::<Vec<usize>>();
+    for (i, ca) in a.iter().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == cb {
+                prev
+            } else {
+                1 + prev.min(row[j]).min(cur)
+            };
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+// 1.0 for the same name once normalized, down to 0.0 for nothing in common
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let (a, b) = (normalize(a), normalize(b));
+    if a == b {
+        return 1.0;
+    }
+    let (a, b) = (a.chars().collect::<Vec<_>>(), b.chars().collect::<Vec<_>>());
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 0.0;
+    }
+    1.0 - levenshtein(&a, &b) as f64 / longest as f64
+}
+
+// a track can have several artists, the best fitting one counts
+fn artist_similarity(artist: &str, candidates: &[&str]) -> f64 {
+    let joined = candidates.join(" & ");
+    candidates
+        .iter()
+        .chain([&joined.as_str()])
+        .map(|c| similarity(artist, c))
+        .fold(0.0, f64::max)
+}
+
+pub struct TrackCandidate<'a> {
+    pub title: &'a str,
+    pub artists: Vec<&'a str>,
+    pub duration_ms: Option<i64>,
+}
+
+// duration only counts when both sides know it
+pub fn score_track(
+    artist: &str,
+    title: &str,
+    duration_ms: Option<i64>,
+    candidate: &TrackCandidate,
+) -> f64 {
+    let title_score = similarity(title, candidate.title);
+    if title_score < MIN_TITLE_SIMILARITY {
+        return 0.0;
+    }
+    let artist_score = artist_similarity(artist, &candidate.artists);
+    match (duration_ms, candidate.duration_ms) {
+        (Some(want), Some(got)) if want > 0 && got > 0 => {
+            let off = ((want - got).abs() as f64 / DURATION_TOLERANCE_MS).min(1.0);
+            0.5 * title_score + 0.35 * artist_score + 0.15 * (1.0 - off)
+        }
+        _ => 0.6 * title_score + 0.4 * artist_score,
+    }
+}
+
+pub fn score_artist(artist: &str, candidate: &str) -> f64 {
+    similarity(artist, candidate)
+}
+
+// highest scoring candidate if it clears the threshold, ties go to the earlier (more popular) one
+pub fn best_match<T>(
+    candidates: impl IntoIterator<Item = T>,
+    threshold: f64,
+    score: impl Fn(&T) -> f64,
+) -> Option<T> {
+    let mut best: Option<(f64, T)> = None;
+    for candidate in candidates {
+        let s = score(&candidate);
+        if s >= threshold && best.as_ref().is_none_or(|(b, _)| s > *b) {
+            best = Some((s, candidate));
+        }
+    }
+    best.map(|(_, c)| c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate<'a>(title: &'a str, artists: Vec<&'a str>) -> TrackCandidate<'a> {
+        TrackCandidate {
+            title,
+            artists,
+            duration_ms: None,
+        }
+    }
+
+    #[test]
+    fn strips_remaster_and_edit_tags() {
+        assert_eq!(
+            normalize("Here Comes the Sun (Remastered 2011)"),
+            "here comes the sun"
+        );
+        assert_eq!(
+            normalize("Here Comes the Sun - Remastered 2011"),
+            "here comes the sun"
+        );
+        assert_eq!(normalize("Blinding Lights - Radio Edit"), "blinding lights");
+        assert_eq!(normalize("Café del Mar [Live]"), "cafe del mar");
+    }
+
+    #[test]
+    fn ignores_featured_artists() {
+        assert_eq!(normalize("Stay (feat. Justin Bieber)"), "stay");
+        assert_eq!(normalize("Stay ft. Justin Bieber"), "stay");
+        assert_eq!(
+            similarity("The Kid LAROI feat. Justin Bieber", "The Kid LAROI"),
+            1.0
+        );
+        let score = score_track(
+            "The Kid LAROI",
+            "Stay (feat. Justin Bieber)",
+            None,
+            &candidate("Stay", vec!["The Kid LAROI", "Justin Bieber"]),
+        );
+        assert!(score >= TRACK_THRESHOLD, "{}", score);
+    }
+
+    #[test]
+    fn same_title_by_another_artist_isnt_a_match() {
+        let wrong = candidate("Yesterday", vec!["Boyz II Men"]);
+        let score = score_track("The Beatles", "Yesterday", None, &wrong);
+        assert!(score < TRACK_THRESHOLD, "{}", score);
+        let right = candidate("Yesterday - Remastered 2009", vec!["The Beatles"]);
+        let best = best_match([wrong, right], TRACK_THRESHOLD, |c| {
+            score_track("The Beatles", "Yesterday", None, c)
+        });
+        assert_eq!(best.unwrap().artists, vec!["The Beatles"]);
+    }
+}
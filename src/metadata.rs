@@ -0,0 +1,367 @@
+use crate::{
+    coverartarchive::CoverArtArchive,
+    error::WrappedErrors,
+    genres::{self, WeightedGenres},
+    lfm::LastFmClient,
+    musicbrainz::MusicBrainzClient,
+};
+use dotenvy;
+use rspotify::ClientCredsSpotify;
+use std::{env, future::Future, pin::Pin};
+
+// lastfm,spotify,musicbrainz,coverartarchive when METADATA_PROVIDERS isnt set
+const DEFAULT_PROVIDERS: &str = "lastfm,spotify,musicbrainz,coverartarchive";
+
+// genres from the main providers that are enough for the fallbacks not to be asked
+const ENOUGH_GENRES: usize = 3;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// what a lookup knows about a track, mbid is the musicbrainz recording id if the scrobble had one
+#[derive(Clone, Copy)]
+pub struct TrackRef<'a> {
+    pub artist: &'a str,
+    pub title: &'a str,
+    pub mbid: Option<&'a str>,
+    // how long the track is when that is already known, helps tell releases apart
+    pub duration_ms: Option<i64>,
+}
+
+// any recording by the artist is enough for musicbrainz to find the artist itself, and its
+// title lets last.fm fall back to the tracks tags when the artist has none
+#[derive(Clone, Copy)]
+pub struct ArtistRef<'a> {
+    pub name: &'a str,
+    pub recording_mbid: Option<&'a str>,
+    pub track_title: Option<&'a str>,
+}
+
+fn unsupported<'a, T: Send + 'a>() -> BoxFuture<'a, Result<Option<T>, WrappedErrors>> {
+    Box::pin(async { Ok(None) })
+}
+
+// somewhere durations, genres and pictures can be looked up. Ok(None) means this provider doesnt
+// know, an error means it couldnt be asked and the answer shouldnt be cached as unknown
+pub trait MetadataProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // in milliseconds
+    fn duration<'a>(
+        &'a self,
+        _track: TrackRef<'a>,
+    ) -> BoxFuture<'a, Result<Option<i64>, WrappedErrors>> {
+        unsupported()
+    }
+
+    // weighted 0 to 1 by how sure the provider is, see genres::rank
+    fn genres<'a>(
+        &'a self,
+        _artist: ArtistRef<'a>,
+    ) -> BoxFuture<'a, Result<Option<WeightedGenres>, WrappedErrors>> {
+        unsupported()
+    }
+
+    // only asked for genres when the other providers dont have enough between them
+    fn genres_fallback(&self) -> bool {
+        false
+    }
+
+    // image urls
+    fn album_art<'a>(
+        &'a self,
+        _track: TrackRef<'a>,
+    ) -> BoxFuture<'a, Result<Option<String>, WrappedErrors>> {
+        unsupported()
+    }
+
+    fn artist_image<'a>(
+        &'a self,
+        _artist: ArtistRef<'a>,
+    ) -> BoxFuture<'a, Result<Option<String>, WrappedErrors>> {
+        unsupported()
+    }
+}
+
+pub struct GenreLookup {
+    // ranked, empty when nobody knew any
+    pub genres: Vec<String>,
+    // false when a provider failed, genres missing what it would have said arent worth caching
+    pub complete: bool,
+}
+
+// asks each provider in turn until one knows, so any of them can be left out or mocked
+pub struct MetadataChain {
+    providers: Vec<Box<dyn MetadataProvider>>,
+}
+
+impl MetadataChain {
+    pub fn new(providers: Vec<Box<dyn MetadataProvider>>) -> Self {
+        Self { providers }
+    }
+
+    // METADATA_PROVIDERS picks the providers and their order, spotify is skipped without a client
+    pub fn from_env(spotify: Option<ClientCredsSpotify>) -> Result<Self, WrappedErrors> {
+        let _ = dotenvy::dotenv();
+        let names = env::var("METADATA_PROVIDERS").unwrap_or(DEFAULT_PROVIDERS.to_string());
+        let mut providers: Vec<Box<dyn MetadataProvider>> = Vec::new();
+        for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match name {
+                // without an api key last.fm is skipped just like spotify
+                "lastfm" => {
+                    if let Ok(client) = LastFmClient::from_env() {
+                        providers.push(Box::new(client));
+                    }
+                }
+                "spotify" => {
+                    if let Some(client) = &spotify {
+                        providers.push(Box::new(client.clone()));
+                    }
+                }
+                "musicbrainz" => providers.push(Box::new(MusicBrainzClient::from_env())),
+                "coverartarchive" => providers.push(Box::new(CoverArtArchive::from_env())),
+                _ => {
+                    return Err(WrappedErrors::Internal(format!(
+                        "unknown metadata provider {}",
+                        name
+                    )))
+                }
+            }
+        }
+        Ok(Self::new(providers))
+    }
+
+    // first answer wins, if nobody knew and someone failed that error is returned instead
+    async fn first<'a, T>(
+        &'a self,
+        ask: impl Fn(&'a dyn MetadataProvider) -> BoxFuture<'a, Result<Option<T>, WrappedErrors>>,
+    ) -> Result<Option<T>, WrappedErrors> {
+        let mut failed = None;
+        for provider in &self.providers {
+            match ask(provider.as_ref()).await {
+                Ok(Some(found)) => return Ok(Some(found)),
+                Ok(None) => continue,
+                Err(e) => {
+                    log::warn!("{} metadata lookup failed: {}", provider.name(), e);
+                    failed.get_or_insert(e);
+                }
+            }
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn duration(&self, track: TrackRef<'_>) -> Result<Option<i64>, WrappedErrors> {
+        self.first(|p| p.duration(track)).await
+    }
+
+    // unlike the rest genres are asked of every provider and merged into one ranked list,
+    // the fallbacks (musicbrainz) only when the others came up with fewer than ENOUGH_GENRES
+    pub async fn genres(&self, artist: ArtistRef<'_>) -> Result<GenreLookup, WrappedErrors> {
+        let mut found: Vec<WeightedGenres> = Vec::new();
+        let mut failed = None;
+        for fallback in [false, true] {
+            if fallback && genres::rank(found.clone()).len() >= ENOUGH_GENRES {
+                break;
+            }
+            let providers = self
+                .providers
+                .iter()
+                .filter(|p| p.genres_fallback() == fallback);
+            for provider in providers {
+                match provider.genres(artist).await {
+                    Ok(Some(genres)) => found.push(genres),
+                    Ok(None) => continue,
+                    Err(e) => {
+                        log::warn!("{} metadata lookup failed: {}", provider.name(), e);
+                        failed.get_or_insert(e);
+                    }
+                }
+            }
+        }
+        let ranked = genres::rank(found);
+        match failed {
+            Some(e) if ranked.is_empty() => Err(e),
+            _ => Ok(GenreLookup {
+                genres: ranked,
+                complete: failed.is_none(),
+            }),
+        }
+    }
+
+    pub async fn album_art(&self, track: TrackRef<'_>) -> Result<Option<String>, WrappedErrors> {
+        self.first(|p| p.album_art(track)).await
+    }
+
+    pub async fn artist_image(
+        &self,
+        artist: ArtistRef<'_>,
+    ) -> Result<Option<String>, WrappedErrors> {
+        self.first(|p| p.artist_image(artist)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // answers from a fixed list, None for a provider that doesnt know and Err for one that failed
+    struct Stub {
+        name: &'static str,
+        duration: Option<Result<i64, ()>>,
+        genres: Option<Result<WeightedGenres, ()>>,
+        fallback: bool,
+    }
+
+    impl Stub {
+        fn new(name: &'static str) -> Self {
+            Self {
+                name,
+                duration: None,
+                genres: None,
+                fallback: false,
+            }
+        }
+    }
+
+    fn answer<T: Clone>(name: &str, a: &Option<Result<T, ()>>) -> Result<Option<T>, WrappedErrors> {
+        match a {
+            Some(Ok(v)) => Ok(Some(v.clone())),
+            Some(Err(())) => Err(WrappedErrors::Upstream(name.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    impl MetadataProvider for Stub {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn duration<'a>(
+            &'a self,
+            _track: TrackRef<'a>,
+        ) -> BoxFuture<'a, Result<Option<i64>, WrappedErrors>> {
+            Box::pin(async move { answer(self.name, &self.duration) })
+        }
+
+        fn genres<'a>(
+            &'a self,
+            _artist: ArtistRef<'a>,
+        ) -> BoxFuture<'a, Result<Option<WeightedGenres>, WrappedErrors>> {
+            Box::pin(async move { answer(self.name, &self.genres) })
+        }
+
+        fn genres_fallback(&self) -> bool {
+            self.fallback
+        }
+    }
+
+    const TRACK: TrackRef = TrackRef {
+        artist: "Artist",
+        title: "Song",
+        mbid: None,
+        duration_ms: None,
+    };
+
+    const ARTIST: ArtistRef = ArtistRef {
+        name: "Artist",
+        recording_mbid: None,
+        track_title: None,
+    };
+
+    fn weighted(names: &[&str]) -> WeightedGenres {
+        names.iter().map(|n| (n.to_string(), 1.0)).collect()
+    }
+
+    fn chain(stubs: Vec<Stub>) -> MetadataChain {
+        MetadataChain::new(
+            stubs
+                .into_iter()
+                .map(|s| Box::new(s) as Box<dyn MetadataProvider>)
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn first_answer_wins_over_earlier_failures() {
+        let failing = Stub {
+            duration: Some(Err(())),
+            ..Stub::new("failing")
+        };
+        let unknowing = Stub::new("unknowing");
+        let knowing = Stub {
+            duration: Some(Ok(180000)),
+            ..Stub::new("knowing")
+        };
+        let found = chain(vec![failing, unknowing, knowing])
+            .duration(TRACK)
+            .await;
+        assert!(matches!(found, Ok(Some(180000))));
+    }
+
+    #[tokio::test]
+    async fn first_returns_the_failure_when_nobody_knew() {
+        let failing = Stub {
+            duration: Some(Err(())),
+            ..Stub::new("failing")
+        };
+        let found = chain(vec![Stub::new("unknowing"), failing])
+            .duration(TRACK)
+            .await;
+        assert!(matches!(found, Err(WrappedErrors::Upstream(name)) if name == "failing"));
+        assert!(matches!(chain(vec![]).duration(TRACK).await, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn fallbacks_are_only_asked_when_genres_are_short() {
+        let fallback = Stub {
+            genres: Some(Ok(vec![("jazz".to_string(), 0.5)])),
+            fallback: true,
+            ..Stub::new("fallback")
+        };
+        let few = Stub {
+            genres: Some(Ok(weighted(&["rock", "pop"]))),
+            ..Stub::new("few")
+        };
+        let found = chain(vec![fallback, few]).genres(ARTIST).await.unwrap();
+        assert_eq!(found.genres, vec!["pop", "rock", "jazz"]);
+        assert!(found.complete);
+
+        let fallback = Stub {
+            genres: Some(Ok(vec![("jazz".to_string(), 0.5)])),
+            fallback: true,
+            ..Stub::new("fallback")
+        };
+        let enough = Stub {
+            genres: Some(Ok(weighted(&["rock", "pop", "metal"]))),
+            ..Stub::new("enough")
+        };
+        let found = chain(vec![fallback, enough]).genres(ARTIST).await.unwrap();
+        assert_eq!(found.genres, vec!["metal", "pop", "rock"]);
+    }
+
+    #[tokio::test]
+    async fn genres_missing_a_failed_provider_arent_complete() {
+        let failing = Stub {
+            genres: Some(Err(())),
+            ..Stub::new("failing")
+        };
+        let knowing = Stub {
+            genres: Some(Ok(weighted(&["rock"]))),
+            ..Stub::new("knowing")
+        };
+        let found = chain(vec![failing, knowing]).genres(ARTIST).await.unwrap();
+        assert_eq!(found.genres, vec!["rock"]);
+        assert!(!found.complete);
+
+        let failing = Stub {
+            genres: Some(Err(())),
+            ..Stub::new("failing")
+        };
+        let found = chain(vec![failing, Stub::new("unknowing")])
+            .genres(ARTIST)
+            .await;
+        assert!(found.is_err());
+    }
+}
@@ -0,0 +1,346 @@
+use crate::{
+    error::WrappedErrors,
+    genres::WeightedGenres,
+    matching::{self, TrackCandidate, ARTIST_THRESHOLD, TRACK_THRESHOLD},
+    metadata::{ArtistRef, BoxFuture, MetadataProvider, TrackRef},
+    ratelimit::{backoff, rate_from_env, RateLimiter},
+};
+use dotenvy;
+use reqwest::StatusCode;
+use serde_json::Value;
+use std::{env, sync::OnceLock};
+
+const DEFAULT_BASE_URL: &str = "https://musicbrainz.org/ws/2";
+// musicbrainz blocks clients that dont say who they are
+const DEFAULT_USER_AGENT: &str = concat!(
+    "lastfmwrapped/",
+    env!("CARGO_PKG_VERSION"),
+    " ( https://github.com/miaaaa0a/lastfmwrapped )"
+);
+const MAX_ATTEMPTS: u32 = 3;
+// search results looked at per lookup
+const SEARCH_LIMIT: &str = "5";
+
+// MUSICBRAINZ_REQUESTS_PER_SECOND, musicbrainz allows one a second on average
+fn limiter() -> &'static RateLimiter {
+    static LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+    LIMITER.get_or_init(|| {
+        let rate = rate_from_env("MUSICBRAINZ_REQUESTS_PER_SECOND", 1.0);
+        RateLimiter::new(rate, 1.0)
+    })
+}
+
+// quotes and the rest of lucenes syntax would otherwise end up as part of the query
+fn lucene_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "+-&|!(){}[]^\"~*?:\\/".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn artist_names(recording: &Value) -> Vec<&str> {
+    recording["artist-credit"]
+        .as_array()
+        .map(|a| a.iter().filter_map(|c| c["name"].as_str()).collect())
+        .unwrap_or_default()
+}
+
+#[derive(Clone)]
+pub struct MusicBrainzClient {
+    http: reqwest::Client,
+    base_url: String,
+}
+
+impl MusicBrainzClient {
+    pub fn new(base_url: &str, user_agent: &str) -> Self {
+        Self {
+            http: reqwest::Client::builder()
+                .user_agent(user_agent)
+                .build()
+                .unwrap_or_default(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    // MUSICBRAINZ_BASE_URL can point this at a mock server or a mirror
+    pub fn from_env() -> Self {
+        let _ = dotenvy::dotenv();
+        let base_url = env::var("MUSICBRAINZ_BASE_URL").unwrap_or(DEFAULT_BASE_URL.to_string());
+        let user_agent =
+            env::var("MUSICBRAINZ_USER_AGENT").unwrap_or(DEFAULT_USER_AGENT.to_string());
+        Self::new(&base_url, &user_agent)
+    }
+
+    // path is everything after /ws/2/, None when there is nothing at that path
+    async fn get_json(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<Option<Value>, WrappedErrors> {
+        let mut attempt = 0;
+        loop {
+            limiter().acquire().await;
+            attempt += 1;
+            let sent = self
+                .http
+                .get(format!("{}/{}", self.base_url, path))
+                .query(&[("fmt", "json")])
+                .query(query)
+                .send()
+                .await;
+            let e = match sent {
+                Ok(resp) if resp.status() == StatusCode::NOT_FOUND => return Ok(None),
+                Ok(resp) if resp.status().is_success() => return Ok(Some(resp.json().await?)),
+                // 503 is how musicbrainz says slow down
+                Ok(resp) if resp.status() == StatusCode::SERVICE_UNAVAILABLE => {
+                    WrappedErrors::RateLimited(format!("musicbrainz: {}", resp.status()))
+                }
+                Ok(resp) if resp.status().is_server_error() => {
+                    WrappedErrors::Upstream(format!("musicbrainz: {}", resp.status()))
+                }
+                Ok(resp) => {
+                    return Err(WrappedErrors::Upstream(format!(
+                        "musicbrainz: {}",
+                        resp.status()
+                    )))
+                }
+                Err(e) => WrappedErrors::Upstream(format!("musicbrainz: {}", e)),
+            };
+            if attempt >= MAX_ATTEMPTS {
+                return Err(e);
+            }
+            let wait = backoff(attempt);
+            if let WrappedErrors::RateLimited(_) = e {
+                limiter().pause(wait);
+            }
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    // the recording the mbid points at, otherwise the best search result for artist and title
+    pub async fn recording(&self, track: TrackRef<'_>) -> Result<Option<Value>, WrappedErrors> {
+        if let Some(mbid) = track.mbid {
+            let path = format!("recording/{}", mbid);
+            let found = self
+                .get_json(&path, &[("inc", "artist-credits+releases")])
+                .await?;
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+        let query = format!(
+            "recording:\"{}\" AND artist:\"{}\"",
+            lucene_escape(track.title),
+            lucene_escape(track.artist)
+        );
+        let results = self
+            .get_json("recording", &[("query", &query), ("limit", SEARCH_LIMIT)])
+            .await?
+            .and_then(|r| r["recordings"].as_array().cloned())
+            .unwrap_or_default();
+        Ok(matching::best_match(results, TRACK_THRESHOLD, |r| {
+            let candidate = TrackCandidate {
+                title: r["title"].as_str().unwrap_or(""),
+                artists: artist_names(r),
+                duration_ms: r["length"].as_i64(),
+            };
+            matching::score_track(track.artist, track.title, track.duration_ms, &candidate)
+        }))
+    }
+
+    // release ids the recording appears on, the cover art archive is keyed by those
+    pub async fn release_ids(&self, track: TrackRef<'_>) -> Result<Vec<String>, WrappedErrors> {
+        Ok(self
+            .recording(track)
+            .await?
+            .and_then(|r| r["releases"].as_array().cloned())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|r| r["id"].as_str().map(String::from))
+            .collect())
+    }
+
+    async fn artist_id(&self, artist: ArtistRef<'_>) -> Result<Option<String>, WrappedErrors> {
+        if let Some(mbid) = artist.recording_mbid {
+            let path = format!("recording/{}", mbid);
+            let recording = self.get_json(&path, &[("inc", "artist-credits")]).await?;
+            let credited = recording
+                .as_ref()
+                .and_then(|r| r["artist-credit"][0]["artist"]["id"].as_str());
+            if let Some(id) = credited {
+                return Ok(Some(id.to_string()));
+            }
+        }
+        let query = format!("artist:\"{}\"", lucene_escape(artist.name));
+        let results = self
+            .get_json("artist", &[("query", &query), ("limit", SEARCH_LIMIT)])
+            .await?
+            .and_then(|r| r["artists"].as_array().cloned())
+            .unwrap_or_default();
+        Ok(matching::best_match(results, ARTIST_THRESHOLD, |a| {
+            matching::score_artist(artist.name, a["name"].as_str().unwrap_or(""))
+        })
+        .and_then(|a| a["id"].as_str().map(String::from)))
+    }
+
+    // curated genres when the artist has any, user tags otherwise, weighted by votes
+    pub async fn artist_genres(
+        &self,
+        artist: ArtistRef<'_>,
+    ) -> Result<Option<WeightedGenres>, WrappedErrors> {
+        let id = match self.artist_id(artist).await? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let found = self
+            .get_json(&format!("artist/{}", id), &[("inc", "genres+tags")])
+            .await?;
+        let found = match found {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let mut genres = found["genres"].as_array().cloned().unwrap_or_default();
+        if genres.is_empty() {
+            genres = found["tags"].as_array().cloned().unwrap_or_default();
+        }
+        let most = genres
+            .iter()
+            .filter_map(|g| g["count"].as_i64())
+            .max()
+            .unwrap_or(0);
+        if most <= 0 {
+            return Ok(None);
+        }
+        Ok(Some(
+            genres
+                .iter()
+                .filter_map(|g| {
+                    let count = g["count"].as_i64().filter(|c| *c > 0)?;
+                    Some((g["name"].as_str()?.to_string(), count as f64 / most as f64))
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl MetadataProvider for MusicBrainzClient {
+    fn name(&self) -> &'static str {
+        "musicbrainz"
+    }
+
+    fn duration<'a>(
+        &'a self,
+        track: TrackRef<'a>,
+    ) -> BoxFuture<'a, Result<Option<i64>, WrappedErrors>> {
+        Box::pin(async move {
+            Ok(self
+                .recording(track)
+                .await?
+                .and_then(|r| r["length"].as_i64())
+                .filter(|l| *l > 0))
+        })
+    }
+
+    fn genres<'a>(
+        &'a self,
+        artist: ArtistRef<'a>,
+    ) -> BoxFuture<'a, Result<Option<WeightedGenres>, WrappedErrors>> {
+        Box::pin(self.artist_genres(artist))
+    }
+
+    // one request a second, and its tags mostly repeat what last.fm already said
+    fn genres_fallback(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn client(server: &MockServer) -> MusicBrainzClient {
+        MusicBrainzClient::new(&format!("{}/ws/2", server.uri()), "test")
+    }
+
+    #[tokio::test]
+    async fn searches_when_the_mbid_is_unknown() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/ws/2/recording/gone"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/ws/2/recording"))
+            .and(query_param("fmt", "json"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({"recordings": [
+                    {"title": "Other", "artist-credit": [{"name": "Someone"}], "length": 1000},
+                    {"title": "Song", "artist-credit": [{"name": "Artist"}], "length": 180000}
+                ]})),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        let duration = client(&server)
+            .duration(TrackRef {
+                artist: "Artist",
+                title: "Song",
+                mbid: Some("gone"),
+                duration_ms: None,
+            })
+            .await;
+        assert!(matches!(duration, Ok(Some(180000))));
+    }
+
+    #[tokio::test]
+    async fn genres_fall_back_to_tags_weighted_by_votes() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/ws/2/artist"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"artists": [
+                {"id": "a1", "name": "Artist"}
+            ]})))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/ws/2/artist/a1"))
+            .and(query_param("inc", "genres+tags"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "genres": [],
+                "tags": [
+                    {"name": "rock", "count": 4},
+                    {"name": "pop", "count": 2},
+                    {"name": "downvoted", "count": -1}
+                ]
+            })))
+            .mount(&server)
+            .await;
+        let genres = client(&server)
+            .artist_genres(ArtistRef {
+                name: "Artist",
+                recording_mbid: None,
+                track_title: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            genres,
+            Some(vec![("rock".to_string(), 1.0), ("pop".to_string(), 0.5)])
+        );
+    }
+}
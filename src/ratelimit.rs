@@ -0,0 +1,92 @@
+use dotenvy;
+use std::{
+    env,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+    // set from Retry-After and the like, nobody sends anything before then
+    paused_until: Option<Instant>,
+}
+
+// token bucket shared by every request to one api, so parallel jobs dont add up to a 429
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<TokenBucket>,
+}
+
+impl RateLimiter {
+    // rate is requests per second, burst how many can go out at once after a quiet spell
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            bucket: Mutex::new(TokenBucket {
+                tokens: burst,
+                refilled_at: Instant::now(),
+                paused_until: None,
+            }),
+        }
+    }
+
+    // None when a token was taken, otherwise how long to wait before trying again
+    fn take(&self) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        if let Some(until) = bucket.paused_until {
+            if until > now {
+                return Some(until - now);
+            }
+            bucket.paused_until = None;
+        }
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.refilled_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    pub async fn acquire(&self) {
+        while let Some(wait) = self.take() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    // holds back every request for a while, for when the api says to slow down
+    pub fn pause(&self, wait: Duration) {
+        let until = Instant::now() + wait;
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.paused_until.is_none_or(|p| p < until) {
+            bucket.paused_until = Some(until);
+        }
+    }
+}
+
+// requests per second from the environment, default when unset or not a positive number
+pub fn rate_from_env(var: &str, default: f64) -> f64 {
+    let _ = dotenvy::dotenv();
+    env::var(var)
+        .ok()
+        .and_then(|r| r.parse::<f64>().ok())
+        .filter(|r| *r > 0.0)
+        .unwrap_or(default)
+}
+
+// 500ms, 1s, 2s, ... with some jitter so retries from parallel jobs dont line up
+pub fn backoff(attempt: u32) -> Duration {
+    let wait = BASE_BACKOFF
+        .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(MAX_BACKOFF);
+    wait + wait.mul_f64(rand::random::<f64>() * 0.25)
+}
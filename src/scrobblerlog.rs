@@ -0,0 +1,82 @@
+use crate::listens::{self, Listen};
+use chrono::{DateTime, TimeZone};
+use chrono_tz::Tz;
+
+// audioscrobbler portable player log (.scrobbler.log) as written by rockbox and friends
+// header lines start with #, every other line is tab separated:
+// artist, album, title, track number, length in seconds, rating (L or S), timestamp, mbid
+pub fn parse_scrobbler_log(text: &str, tz: Tz) -> Vec<Listen> {
+    // devices without a clock zone write local time as if it was utc
+    let local_timestamps = text.lines().any(|l| l.trim() == "#TZ/UNKNOWN");
+
+    text.lines()
+        .filter(|l| !l.starts_with('#') && !l.trim().is_empty())
+        .filter_map(|l| {
+            let fields = l.split('\t').collect::<Vec<&str>>();
+            if fields.len() < 7 {
+                return None;
+            }
+            // skipped tracks were never really listened to
+            if fields[5] != "L" {
+                return None;
+            }
+            let length = fields[4].parse::<i64>().ok()?;
+            let mut timestamp = fields[6].parse::<i64>().ok()?;
+            if local_timestamps {
+                let naive = DateTime::from_timestamp(timestamp, 0)?.naive_utc();
+                timestamp = tz.from_local_datetime(&naive).earliest()?.timestamp();
+            }
+            Some(Listen {
+                artist: fields[0].to_string(),
+                title: fields[2].to_string(),
+                timestamp,
+                played_ms: Some(length * 1000),
+                mbid: listens::mbid(fields.get(7).map(|m| m.trim())),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(tz_header: &str) -> String {
+        [
+            "#AUDIOSCROBBLER/1.1",
+            tz_header,
+            "#CLIENT/Rockbox",
+            "Artist\tAlbum\tSong\t1\t180\tL\t1706704440\tb1a9c0e9-d987-4042-ae91-78d6a3267d69",
+            "Artist\tAlbum\tSkipped\t2\t200\tS\t1706704640\t",
+            "Artist\tAlbum\tNo Timestamp\t3\t200\tL",
+            "Artist\tAlbum\tBad Length\t4\tlong\tL\t1706704840\t",
+        ]
+        .join("\n")
+    }
+
+    #[test]
+    fn keeps_listened_rows_only() {
+        let listens = parse_scrobbler_log(&log("#TZ/UTC"), Tz::Europe__Berlin);
+        assert_eq!(listens.len(), 1);
+        assert_eq!(listens[0].artist, "Artist");
+        assert_eq!(listens[0].title, "Song");
+        assert_eq!(listens[0].played_ms, Some(180_000));
+        assert_eq!(
+            listens[0].mbid.as_deref(),
+            Some("b1a9c0e9-d987-4042-ae91-78d6a3267d69")
+        );
+    }
+
+    #[test]
+    fn utc_logs_are_taken_as_is() {
+        let listens = parse_scrobbler_log(&log("#TZ/UTC"), Tz::Europe__Berlin);
+        assert_eq!(listens[0].timestamp, 1_706_704_440);
+    }
+
+    #[test]
+    fn unknown_zone_logs_are_read_as_local_time() {
+        // berlin is an hour ahead of utc in january
+        let listens = parse_scrobbler_log(&log("#TZ/UNKNOWN"), Tz::Europe__Berlin);
+        assert_eq!(listens[0].timestamp, 1_706_704_440 - 3600);
+    }
+}
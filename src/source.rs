@@ -0,0 +1,100 @@
+use crate::{
+    calculations::{top_5_listened_artists, top_5_listened_tracks},
+    daterange::DateRange,
+    listenbrainz::ListenBrainzClient,
+    listens::Listen,
+    listenstore::{self, StoredSource},
+};
+use chrono::Datelike;
+use std::{collections::HashMap, error::Error, fmt};
+
+#[derive(Debug)]
+pub enum SourceErrors {
+    UnknownSource,
+}
+
+impl fmt::Display for SourceErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for SourceErrors {}
+
+// where a users listens come from
+#[derive(Clone)]
+pub enum ListenSource {
+    // scrobbles are synced into the listen store and read back from there
+    LastFm(String),
+    ListenBrainz(ListenBrainzClient),
+    // listens uploaded through /api/import
+    Import(String),
+}
+
+impl ListenSource {
+    // no source given means last.fm
+    pub fn new(source: Option<&str>, username: &str) -> Result<Self, SourceErrors> {
+        match source {
+            None | Some("lastfm") => Ok(Self::LastFm(username.to_string())),
+            Some("listenbrainz") => Ok(Self::ListenBrainz(ListenBrainzClient::from_env(username))),
+            Some("import") => Ok(Self::Import(username.to_string())),
+            Some(_) => Err(SourceErrors::UnknownSource),
+        }
+    }
+
+    pub async fn listens(&self, range: DateRange) -> Result<Vec<Listen>, Box<dyn Error>> {
+        match self {
+            Self::LastFm(username) => {
+                listenstore::sync_lastfm(username, range).await?;
+                Ok(listenstore::load_listens(username, StoredSource::LastFm, range).await?)
+            }
+            Self::ListenBrainz(client) => client.listens(range).await,
+            Self::Import(username) => {
+                Ok(listenstore::load_listens(username, StoredSource::Import, range).await?)
+            }
+        }
+    }
+
+    pub async fn top_5_tracks(
+        &self,
+        range: DateRange,
+    ) -> Result<HashMap<String, i32>, Box<dyn Error>> {
+        match self {
+            Self::ListenBrainz(client) => match listenbrainz_stats_range(range) {
+                Some(stats_range) => client.top_5_tracks(stats_range).await,
+                None => Ok(top_5_listened_tracks(&client.listens(range).await?)),
+            },
+            Self::LastFm(_) | Self::Import(_) => {
+                Ok(top_5_listened_tracks(&self.listens(range).await?))
+            }
+        }
+    }
+
+    pub async fn top_5_artists(
+        &self,
+        range: DateRange,
+    ) -> Result<HashMap<String, i32>, Box<dyn Error>> {
+        match self {
+            Self::ListenBrainz(client) => match listenbrainz_stats_range(range) {
+                Some(stats_range) => client.top_5_artists(stats_range).await,
+                None => Ok(top_5_listened_artists(&client.listens(range).await?)),
+            },
+            Self::LastFm(_) | Self::Import(_) => {
+                Ok(top_5_listened_artists(&self.listens(range).await?))
+            }
+        }
+    }
+}
+
+// listenbrainz only has stats for whole calendar years, anything else gets counted from listens
+fn listenbrainz_stats_range(range: DateRange) -> Option<&'static str> {
+    let this_year = DateRange::current_year(range.tz);
+    let last_year = DateRange::year(this_year.start().year() - 1, range.tz).ok()?;
+    if (range.from, range.to) == (this_year.from, this_year.to) {
+        Some("this_year")
+    } else if (range.from, range.to) == (last_year.from, last_year.to) {
+        Some("year")
+    } else {
+        None
+    }
+}
@@ -1,115 +1,270 @@
-use crate::defaults::Defaults;
+use crate::{
+    error::WrappedErrors,
+    genres::WeightedGenres,
+    matching::{self, TrackCandidate, ARTIST_THRESHOLD, TRACK_THRESHOLD},
+    metadata::{ArtistRef, BoxFuture, MetadataProvider, TrackRef},
+    ratelimit::{backoff, rate_from_env, RateLimiter},
+};
+use chrono::Utc;
 use dotenvy;
-use rspotify::{model::SearchType, prelude::*, ClientCredsSpotify, Credentials};
-use serde_json::{self, json, Value};
-use std::str::FromStr;
-
-pub async fn auth() -> ClientCredsSpotify {
-    let _ = dotenvy::dotenv();
-    let creds = Credentials::from_env().unwrap();
-    let spotify = ClientCredsSpotify::new(creds);
-    spotify.request_token().await.unwrap();
-    spotify
-}
+use rspotify::{
+    http::HttpError, model::SearchType, prelude::*, ClientCredsSpotify, ClientError, Config,
+    Credentials,
+};
+use serde_json::{self, Value};
+use std::{sync::OnceLock, time::Duration};
+
+// search results looked at per lookup
+const SEARCH_LIMIT: u32 = 5;
+// attempts per search before the last error is handed back
+const MAX_ATTEMPTS: u32 = 5;
+// spotify sometimes asks for hours on a 429, rather fail the request than hang it that long
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+// seconds before it expires that the shared token gets replaced
+const TOKEN_REFRESH_MARGIN: i64 = 60;
 
-pub async fn find_song_duration(c: &ClientCredsSpotify, q: &str, name: &str) -> Option<i64> {
-    let value = c
-        .search(q, SearchType::Track, None, None, Some(1), None)
-        .await;
-    // incredible error handling
-    let search_result = serde_json::to_value(match value.as_ref().err() {
-        Some(_) => return Some(0_i64),
-        None => value.unwrap(),
+// SPOTIFY_REQUESTS_PER_SECOND, 5 by default with bursts of twice that
+fn limiter() -> &'static RateLimiter {
+    static LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+    LIMITER.get_or_init(|| {
+        let rate = rate_from_env("SPOTIFY_REQUESTS_PER_SECOND", 5.0);
+        RateLimiter::new(rate, rate * 2.0)
     })
-    .unwrap();
-    //println!("{} - {}", search_result["tracks"]["items"][0]["artists"][0]["name"], search_result["tracks"]["items"][0]["name"]);
-    return match search_result["tracks"]["items"][0]["name"]
-        .as_str()
-        .unwrap()
-        .to_lowercase()
-        == name.to_lowercase()
-    {
-        true => {
-            //println!("matches");
-            search_result["tracks"]["items"][0]["duration_ms"].as_i64()
-        }
-        false => Some(0_i64),
-    };
-    //println!("{:?}", search_result["tracks"]["items"][0]["duration_ms"]);
-    //return search_result["tracks"]["items"][0]["duration_ms"].as_i64();
 }
 
-pub async fn find_artist_genres(c: &ClientCredsSpotify, q: &str) -> Value {
-    let search_result = serde_json::to_value(loop {
-        if let Ok(result) = c
-            .search(q, SearchType::Artist, None, None, Some(1), None)
-            .await
-        {
-            break result;
+fn is_rate_limited(e: &ClientError) -> bool {
+    match e {
+        ClientError::Http(http) => {
+            matches!(http.as_ref(), HttpError::StatusCode(resp) if resp.status().as_u16() == 429)
         }
-    })
-    .unwrap();
-    return match search_result["artists"]["items"][0]["name"]
-        .as_str()
-        .unwrap_or("")
-        .to_lowercase()
-        == q.to_lowercase()
-    {
-        true => {
-            //println!("matches");
-            search_result["artists"]["items"][0]["genres"].clone()
+        _ => false,
+    }
+}
+
+// how long to wait before trying again, None when trying again wont help
+fn retry_wait(e: &ClientError, attempt: u32) -> Option<Duration> {
+    let http = match e {
+        ClientError::Http(http) => http.as_ref(),
+        _ => return None,
+    };
+    match http {
+        HttpError::StatusCode(resp) if resp.status().as_u16() == 429 => {
+            let retry_after = resp
+                .headers()
+                .get("Retry-After")
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.parse::<u64>().ok())
+                .map(Duration::from_secs);
+            match retry_after {
+                Some(wait) if wait > MAX_RETRY_AFTER => None,
+                Some(wait) => Some(wait),
+                None => Some(backoff(attempt)),
+            }
         }
-        false => {
-            json!(vec![""])
+        HttpError::StatusCode(resp) if resp.status().is_server_error() => Some(backoff(attempt)),
+        HttpError::StatusCode(_) => None,
+        // connection problems and timeouts
+        HttpError::Client(_) => Some(backoff(attempt)),
+    }
+}
+
+// the first few search results for q, as json, matching picks the right one out of them
+async fn search(c: &ClientCredsSpotify, q: &str, t: SearchType) -> Result<Value, WrappedErrors> {
+    let mut attempt = 0;
+    loop {
+        limiter().acquire().await;
+        attempt += 1;
+        let e = match c.search(q, t, None, None, Some(SEARCH_LIMIT), None).await {
+            Ok(result) => return Ok(serde_json::to_value(result)?),
+            Err(e) => e,
+        };
+        let wait = match retry_wait(&e, attempt) {
+            Some(wait) if attempt < MAX_ATTEMPTS => wait,
+            _ if is_rate_limited(&e) => {
+                return Err(WrappedErrors::RateLimited(format!("spotify: {}", e)))
+            }
+            _ => return Err(WrappedErrors::Upstream(format!("spotify: {}", e))),
+        };
+        if is_rate_limited(&e) {
+            limiter().pause(wait);
         }
-    };
+        drop(e);
+        tokio::time::sleep(wait).await;
+    }
+}
+
+// one client credentials token for the whole server instead of one per request, kept in rocket
+// state. the first request to find it about to expire fetches a new one while the rest wait
+pub struct SharedSpotify {
+    // None without credentials in the environment, spotify is left out of lookups then
+    client: Option<ClientCredsSpotify>,
+    refreshing: tokio::sync::Mutex<()>,
 }
 
-pub async fn find_song_cover(c: &ClientCredsSpotify, q: &str, name: &str) -> Value {
-    let search_result = serde_json::to_value(loop {
-        if let Ok(result) = c
-            .search(q, SearchType::Track, None, None, Some(1), None)
-            .await
-        {
-            break result;
+impl SharedSpotify {
+    pub fn from_env() -> Self {
+        let _ = dotenvy::dotenv();
+        // token_refreshing covers jobs that outlive the token they started with
+        let config = Config {
+            token_refreshing: true,
+            ..Default::default()
+        };
+        Self {
+            client: Credentials::from_env().map(|c| ClientCredsSpotify::with_config(c, config)),
+            refreshing: tokio::sync::Mutex::new(()),
         }
-    })
-    .unwrap();
-    //println!("{} - {}", search_result["tracks"]["items"][0]["artists"][0]["name"], search_result["tracks"]["items"][0]["name"]);
-    return match search_result["tracks"]["items"][0]["name"]
-        .as_str()
-        .unwrap()
-        .to_lowercase()
-        == name.to_lowercase()
-    {
-        true => {
-            //println!("matches");
-            search_result["tracks"]["items"][0]["album"]["images"][0].clone()
+    }
+
+    async fn needs_token(client: &ClientCredsSpotify) -> bool {
+        let token = client.get_token();
+        let token = token.lock().await.unwrap();
+        match token.as_ref().and_then(|t| t.expires_at) {
+            Some(expires_at) => {
+                expires_at.timestamp() - TOKEN_REFRESH_MARGIN <= Utc::now().timestamp()
+            }
+            None => true,
         }
-        false => Value::from_str(Defaults::BLACK_IMAGE).unwrap(),
-    };
-    //println!("{:?}", search_result["tracks"]["items"][0]["duration_ms"]);
-    //return search_result["tracks"]["items"][0]["duration_ms"].as_i64();
-}
+    }
 
-pub async fn find_artist_icon(c: &ClientCredsSpotify, q: &str) -> Value {
-    let search_result = serde_json::to_value(loop {
-        if let Ok(result) = c
-            .search(q, SearchType::Artist, None, None, Some(1), None)
-            .await
-        {
-            break result;
+    // clones share the token, so a refresh done through any of them counts for all
+    pub async fn client(&self) -> Result<Option<ClientCredsSpotify>, WrappedErrors> {
+        let client = match &self.client {
+            Some(client) => client,
+            None => return Ok(None),
+        };
+        if Self::needs_token(client).await {
+            let _refreshing = self.refreshing.lock().await;
+            // someone else may have refreshed it while this was waiting for the lock
+            if Self::needs_token(client).await {
+                client
+                    .request_token()
+                    .await
+                    .map_err(|e| WrappedErrors::Upstream(format!("spotify: {}", e)))?;
+            }
         }
-    })
-    .unwrap();
-    if search_result["artists"]["items"][0]["name"]
-        .as_str()
-        .unwrap()
-        .to_lowercase()
-        == q.to_lowercase()
-    {
-        search_result["artists"]["items"][0]["images"][0].clone()
-    } else {
-        Value::from_str(Defaults::BLACK_IMAGE).unwrap()
+        Ok(Some(client.clone()))
+    }
+}
+
+// the search result that best matches artist and title, None when none is close enough
+async fn find_track(
+    c: &ClientCredsSpotify,
+    artist: &str,
+    title: &str,
+    duration_ms: Option<i64>,
+) -> Result<Option<Value>, WrappedErrors> {
+    let search_result = search(c, &format!("{} {}", artist, title), SearchType::Track).await?;
+    let items = search_result["tracks"]["items"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    Ok(matching::best_match(items, TRACK_THRESHOLD, |t| {
+        let candidate = TrackCandidate {
+            title: t["name"].as_str().unwrap_or(""),
+            artists: t["artists"]
+                .as_array()
+                .map(|a| a.iter().filter_map(|a| a["name"].as_str()).collect())
+                .unwrap_or_default(),
+            duration_ms: t["duration_ms"].as_i64(),
+        };
+        matching::score_track(artist, title, duration_ms, &candidate)
+    }))
+}
+
+async fn find_artist(c: &ClientCredsSpotify, artist: &str) -> Result<Option<Value>, WrappedErrors> {
+    let search_result = search(c, artist, SearchType::Artist).await?;
+    let items = search_result["artists"]["items"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    Ok(matching::best_match(items, ARTIST_THRESHOLD, |a| {
+        matching::score_artist(artist, a["name"].as_str().unwrap_or(""))
+    }))
+}
+
+// None when spotify doesnt have the track
+pub async fn find_song_duration(
+    c: &ClientCredsSpotify,
+    artist: &str,
+    title: &str,
+) -> Result<Option<i64>, WrappedErrors> {
+    let track = find_track(c, artist, title, None).await?;
+    Ok(track.and_then(|t| t["duration_ms"].as_i64()))
+}
+
+// None when spotify doesnt know the artist or has no genres for them
+pub async fn find_artist_genres(
+    c: &ClientCredsSpotify,
+    q: &str,
+) -> Result<Option<Vec<String>>, WrappedErrors> {
+    let genres = find_artist(c, q)
+        .await?
+        .and_then(|a| a["genres"].as_array().cloned())
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|g| g.as_str().map(String::from))
+        .collect::<Vec<_>>();
+    Ok((!genres.is_empty()).then_some(genres))
+}
+
+// url of the album cover, a known duration helps tell releases apart
+pub async fn find_song_cover(
+    c: &ClientCredsSpotify,
+    artist: &str,
+    title: &str,
+    duration_ms: Option<i64>,
+) -> Result<Option<String>, WrappedErrors> {
+    let track = find_track(c, artist, title, duration_ms).await?;
+    Ok(track.and_then(|t| t["album"]["images"][0]["url"].as_str().map(String::from)))
+}
+
+pub async fn find_artist_icon(
+    c: &ClientCredsSpotify,
+    q: &str,
+) -> Result<Option<String>, WrappedErrors> {
+    let artist = find_artist(c, q).await?;
+    Ok(artist.and_then(|a| a["images"][0]["url"].as_str().map(String::from)))
+}
+
+impl MetadataProvider for ClientCredsSpotify {
+    fn name(&self) -> &'static str {
+        "spotify"
+    }
+
+    fn duration<'a>(
+        &'a self,
+        track: TrackRef<'a>,
+    ) -> BoxFuture<'a, Result<Option<i64>, WrappedErrors>> {
+        Box::pin(find_song_duration(self, track.artist, track.title))
+    }
+
+    // spotify genres are curated and unordered, all of them count fully
+    fn genres<'a>(
+        &'a self,
+        artist: ArtistRef<'a>,
+    ) -> BoxFuture<'a, Result<Option<WeightedGenres>, WrappedErrors>> {
+        Box::pin(async move {
+            let genres = find_artist_genres(self, artist.name).await?;
+            Ok(genres.map(|g| g.into_iter().map(|g| (g, 1.0)).collect()))
+        })
+    }
+
+    fn album_art<'a>(
+        &'a self,
+        track: TrackRef<'a>,
+    ) -> BoxFuture<'a, Result<Option<String>, WrappedErrors>> {
+        Box::pin(find_song_cover(
+            self,
+            track.artist,
+            track.title,
+            track.duration_ms,
+        ))
+    }
+
+    fn artist_image<'a>(
+        &'a self,
+        artist: ArtistRef<'a>,
+    ) -> BoxFuture<'a, Result<Option<String>, WrappedErrors>> {
+        Box::pin(find_artist_icon(self, artist.name))
     }
 }
@@ -0,0 +1,65 @@
+use crate::listens::Listen;
+use chrono::DateTime;
+use serde::Deserialize;
+
+// one entry of Streaming_History_Audio_*.json from spotifys extended streaming history export
+#[derive(Deserialize)]
+struct StreamingHistoryEntry {
+    // time the play ended
+    ts: String,
+    ms_played: i64,
+    master_metadata_track_name: Option<String>,
+    master_metadata_album_artist_name: Option<String>,
+}
+
+// podcast episodes and entries with nothing played are skipped
+pub fn parse_streaming_history(text: &str) -> Result<Vec<Listen>, serde_json::Error> {
+    let entries: Vec<StreamingHistoryEntry> = serde_json::from_str(text)?;
+    let listens = entries
+        .into_iter()
+        .filter(|e| e.ms_played > 0)
+        .filter_map(|e| {
+            let ended = DateTime::parse_from_rfc3339(&e.ts).ok()?.timestamp();
+            Some(Listen {
+                artist: e.master_metadata_album_artist_name?,
+                title: e.master_metadata_track_name?,
+                timestamp: ended - e.ms_played / 1000,
+                played_ms: Some(e.ms_played),
+                mbid: None,
+            })
+        })
+        .collect();
+    Ok(listens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_songs_that_were_played() {
+        let history = r#"[
+            {"ts": "2024-03-01T12:03:20Z", "ms_played": 200000,
+             "master_metadata_track_name": "Song", "master_metadata_album_artist_name": "Artist"},
+            {"ts": "2024-03-01T12:10:00Z", "ms_played": 0,
+             "master_metadata_track_name": "Skipped", "master_metadata_album_artist_name": "Artist"},
+            {"ts": "2024-03-01T13:00:00Z", "ms_played": 1800000,
+             "master_metadata_track_name": null, "master_metadata_album_artist_name": null},
+            {"ts": "yesterday", "ms_played": 1000,
+             "master_metadata_track_name": "Bad Date", "master_metadata_album_artist_name": "Artist"}
+        ]"#;
+        let listens = parse_streaming_history(history).unwrap();
+        assert_eq!(listens.len(), 1);
+        assert_eq!(listens[0].artist, "Artist");
+        assert_eq!(listens[0].title, "Song");
+        // ts is when the play ended
+        assert_eq!(listens[0].timestamp, 1_709_294_600 - 200);
+        assert_eq!(listens[0].played_ms, Some(200000));
+    }
+
+    #[test]
+    fn rejects_files_that_arent_streaming_history() {
+        assert!(parse_streaming_history("{\"ts\": 1}").is_err());
+        assert!(parse_streaming_history("[{\"ms_played\": 10}]").is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use crate::{
+    calculations::{
+        calculate_listens_genre_periods, calculate_listens_year, largest_value_hashmap,
+        top_5_listened_artists, top_5_listened_tracks, GenreSeries, GenreShare,
+    },
+    daterange::{DateRange, Period},
+    jobs::Progress,
+    metadata::MetadataChain,
+    source::ListenSource,
+};
+use chrono::TimeZone;
+use serde::Serialize;
+use std::{collections::HashMap, error::Error};
+
+#[derive(Serialize)]
+pub struct DayMinutes {
+    // local midnight of the day in the requested time zone
+    pub timestamp: i64,
+    pub date: String,
+    pub minutes: i64,
+}
+
+#[derive(Serialize)]
+pub struct PlayCount {
+    pub name: String,
+    pub playcount: i32,
+}
+
+#[derive(Serialize)]
+pub struct ArtistGenres {
+    pub artist: String,
+    pub genres: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct GenrePeriodStats {
+    pub label: String,
+    pub from: i64,
+    pub to: i64,
+    pub artists: Vec<ArtistGenres>,
+    pub genres: Vec<GenreShare>,
+}
+
+// everything the slides are drawn from, without drawing them
+#[derive(Serialize)]
+pub struct Stats {
+    pub from: i64,
+    pub to: i64,
+    pub tz: String,
+    pub scrobbles: usize,
+    pub total_minutes: i64,
+    // None when nothing was listened to in the range
+    pub busiest_day: Option<DayMinutes>,
+    pub days: Vec<DayMinutes>,
+    pub top_tracks: Vec<PlayCount>,
+    pub top_artists: Vec<PlayCount>,
+    pub genre_periods: Vec<GenrePeriodStats>,
+    // what imageprocessing::genre_evolution wants
+    #[serde(skip)]
+    pub series: GenreSeries,
+}
+
+fn day_minutes(range: DateRange, timestamp: i64, ms: i64) -> DayMinutes {
+    DayMinutes {
+        timestamp,
+        date: range
+            .tz
+            .timestamp_opt(timestamp, 0)
+            .unwrap()
+            .format("%Y-%m-%d")
+            .to_string(),
+        minutes: (ms / 1000) / 60,
+    }
+}
+
+// most played first
+fn play_counts(plays: HashMap<String, i32>) -> Vec<PlayCount> {
+    let mut counts = plays
+        .into_iter()
+        .map(|(name, playcount)| PlayCount { name, playcount })
+        .collect::<Vec<_>>();
+    counts.sort_by(|a, b| b.playcount.cmp(&a.playcount).then(a.name.cmp(&b.name)));
+    counts
+}
+
+fn genre_periods(series: &GenreSeries) -> Vec<GenrePeriodStats> {
+    series
+        .periods
+        .iter()
+        .map(|p| GenrePeriodStats {
+            label: p.period.label.clone(),
+            from: p.period.range.from,
+            to: p.period.range.to,
+            artists: p
+                .genres
+                .artists
+                .iter()
+                .map(|(artist, genres)| ArtistGenres {
+                    artist: artist.clone(),
+                    genres: genres.clone(),
+                })
+                .collect(),
+            genres: p.genres.genres.clone(),
+        })
+        .collect()
+}
+
+// fetches the listens once and works everything out from them
+pub async fn calculate_stats(
+    source: &ListenSource,
+    metadata: &MetadataChain,
+    range: DateRange,
+    periods: &[Period],
+    progress: &Progress,
+) -> Result<Stats, Box<dyn Error>> {
+    progress.phase("fetching scrobbles", 0);
+    let listens = source.listens(range).await?;
+    progress.phase(&format!("fetched {} scrobbles", listens.len()), 0);
+    let total = calculate_listens_year(&listens, metadata, range, progress).await?;
+    let series = calculate_listens_genre_periods(&listens, metadata, periods, progress).await?;
+
+    let busiest = largest_value_hashmap(&total);
+    let mut days = total
+        .iter()
+        .map(|(ts, ms)| day_minutes(range, *ts, *ms))
+        .collect::<Vec<_>>();
+    days.sort_by_key(|d| d.timestamp);
+
+    Ok(Stats {
+        from: range.from,
+        to: range.to,
+        tz: range.tz.name().to_string(),
+        scrobbles: listens.len(),
+        total_minutes: ((total.values().sum::<i64>()) / 1000) / 60,
+        busiest_day: (!total.is_empty()).then(|| day_minutes(range, busiest[0], busiest[1])),
+        days,
+        top_tracks: play_counts(top_5_listened_tracks(&listens)),
+        top_artists: play_counts(top_5_listened_artists(&listens)),
+        genre_periods: genre_periods(&series),
+        series,
+    })
+}
@@ -0,0 +1,459 @@
+use dotenvy;
+use std::{collections::HashMap, env, sync::OnceLock};
+use titlecase::titlecase;
+
+// canonical genre and the broader genre it belongs to, families have no parent.
+// three levels deep at most: family > genre > subgenre
+const GENRES: &[(&str, Option<&str>)] = &[
+    // families
+    ("rock", None),
+    ("pop", None),
+    ("hip hop", None),
+    ("electronic", None),
+    ("r&b", None),
+    ("jazz", None),
+    ("classical", None),
+    ("metal", None),
+    ("punk", None),
+    ("folk", None),
+    ("country", None),
+    ("blues", None),
+    ("reggae", None),
+    ("latin", None),
+    ("soul", None),
+    ("funk", None),
+    ("experimental", None),
+    ("soundtrack", None),
+    // rock
+    ("indie rock", Some("rock")),
+    ("alternative rock", Some("rock")),
+    ("classic rock", Some("rock")),
+    ("hard rock", Some("rock")),
+    ("psychedelic rock", Some("rock")),
+    ("progressive rock", Some("rock")),
+    ("post rock", Some("rock")),
+    ("garage rock", Some("rock")),
+    ("shoegaze", Some("rock")),
+    ("grunge", Some("rock")),
+    ("britpop", Some("rock")),
+    ("math rock", Some("indie rock")),
+    ("midwest emo", Some("emo")),
+    ("emo", Some("rock")),
+    ("pop rock", Some("rock")),
+    ("soft rock", Some("rock")),
+    ("country rock", Some("rock")),
+    ("rap rock", Some("rock")),
+    ("surf rock", Some("garage rock")),
+    ("stoner rock", Some("hard rock")),
+    ("dream pop", Some("shoegaze")),
+    ("noise rock", Some("alternative rock")),
+    // pop
+    ("indie pop", Some("pop")),
+    ("synthpop", Some("pop")),
+    ("dance pop", Some("pop")),
+    ("art pop", Some("pop")),
+    ("electropop", Some("pop")),
+    ("k pop", Some("pop")),
+    ("j pop", Some("pop")),
+    ("hyperpop", Some("pop")),
+    ("teen pop", Some("pop")),
+    ("city pop", Some("pop")),
+    ("chamber pop", Some("indie pop")),
+    ("bedroom pop", Some("indie pop")),
+    ("twee pop", Some("indie pop")),
+    ("power pop", Some("pop")),
+    ("country pop", Some("pop")),
+    // hip hop
+    ("rap", Some("hip hop")),
+    ("trap", Some("hip hop")),
+    ("drill", Some("hip hop")),
+    ("boom bap", Some("hip hop")),
+    ("conscious hip hop", Some("hip hop")),
+    ("alternative hip hop", Some("hip hop")),
+    ("gangsta rap", Some("rap")),
+    ("cloud rap", Some("rap")),
+    ("emo rap", Some("rap")),
+    ("jazz rap", Some("rap")),
+    ("pop rap", Some("rap")),
+    ("country rap", Some("rap")),
+    ("grime", Some("hip hop")),
+    ("phonk", Some("trap")),
+    // electronic
+    ("house", Some("electronic")),
+    ("techno", Some("electronic")),
+    ("drum and bass", Some("electronic")),
+    ("dubstep", Some("electronic")),
+    ("trance", Some("electronic")),
+    ("ambient", Some("electronic")),
+    ("idm", Some("electronic")),
+    ("edm", Some("electronic")),
+    ("synthwave", Some("electronic")),
+    ("vaporwave", Some("electronic")),
+    ("breakbeat", Some("electronic")),
+    ("downtempo", Some("electronic")),
+    ("electro", Some("electronic")),
+    ("deep house", Some("house")),
+    ("tech house", Some("house")),
+    ("progressive house", Some("house")),
+    ("uk garage", Some("electronic")),
+    ("jungle", Some("drum and bass")),
+    ("footwork", Some("electronic")),
+    ("hardstyle", Some("electronic")),
+    ("lo fi", Some("downtempo")),
+    ("trip hop", Some("downtempo")),
+    ("chillwave", Some("synthwave")),
+    // r&b, soul, funk
+    ("contemporary r&b", Some("r&b")),
+    ("alternative r&b", Some("r&b")),
+    ("neo soul", Some("soul")),
+    ("motown", Some("soul")),
+    ("disco", Some("funk")),
+    ("gospel", Some("soul")),
+    // jazz
+    ("bebop", Some("jazz")),
+    ("smooth jazz", Some("jazz")),
+    ("jazz fusion", Some("jazz")),
+    ("swing", Some("jazz")),
+    ("bossa nova", Some("jazz")),
+    ("jazz funk", Some("jazz")),
+    ("soul jazz", Some("jazz")),
+    ("jazz rock", Some("jazz fusion")),
+    // classical
+    ("baroque", Some("classical")),
+    ("opera", Some("classical")),
+    ("contemporary classical", Some("classical")),
+    ("minimalism", Some("classical")),
+    // metal
+    ("heavy metal", Some("metal")),
+    ("thrash metal", Some("metal")),
+    ("death metal", Some("metal")),
+    ("black metal", Some("metal")),
+    ("doom metal", Some("metal")),
+    ("metalcore", Some("metal")),
+    ("nu metal", Some("metal")),
+    ("progressive metal", Some("metal")),
+    ("power metal", Some("metal")),
+    ("deathcore", Some("death metal")),
+    ("djent", Some("progressive metal")),
+    ("sludge metal", Some("doom metal")),
+    ("folk metal", Some("metal")),
+    ("rap metal", Some("nu metal")),
+    // punk
+    ("pop punk", Some("punk")),
+    ("post punk", Some("punk")),
+    ("hardcore punk", Some("punk")),
+    ("skate punk", Some("punk")),
+    ("ska punk", Some("punk")),
+    ("riot grrrl", Some("punk")),
+    ("post hardcore", Some("hardcore punk")),
+    ("new wave", Some("post punk")),
+    ("folk punk", Some("punk")),
+    ("dance punk", Some("post punk")),
+    // folk, country, blues
+    ("indie folk", Some("folk")),
+    ("folk rock", Some("folk")),
+    ("singer songwriter", Some("folk")),
+    ("americana", Some("country")),
+    ("bluegrass", Some("country")),
+    ("alt country", Some("country")),
+    ("delta blues", Some("blues")),
+    ("blues rock", Some("blues")),
+    // reggae, latin
+    ("dancehall", Some("reggae")),
+    ("dub", Some("reggae")),
+    ("ska", Some("reggae")),
+    ("reggaeton", Some("latin")),
+    ("salsa", Some("latin")),
+    ("cumbia", Some("latin")),
+    ("latin pop", Some("latin")),
+    ("bachata", Some("latin")),
+    ("corridos", Some("latin")),
+    // experimental
+    ("noise", Some("experimental")),
+    ("industrial", Some("experimental")),
+    ("drone", Some("experimental")),
+    ("avant garde", Some("experimental")),
+    // soundtrack
+    ("video game music", Some("soundtrack")),
+    ("anime", Some("soundtrack")),
+    ("musicals", Some("soundtrack")),
+];
+
+// other spellings and names for the canonical genres above
+const ALIASES: &[(&str, &str)] = &[
+    ("hiphop", "hip hop"),
+    ("hip hop music", "hip hop"),
+    ("rnb", "r&b"),
+    ("rhythm and blues", "r&b"),
+    ("electronica", "electronic"),
+    ("electronic music", "electronic"),
+    ("dance", "edm"),
+    ("electronic dance music", "edm"),
+    ("indie", "indie rock"),
+    ("indiepop", "indie pop"),
+    ("alternative", "alternative rock"),
+    ("alt rock", "alternative rock"),
+    ("prog rock", "progressive rock"),
+    ("prog", "progressive rock"),
+    ("prog metal", "progressive metal"),
+    ("postrock", "post rock"),
+    ("postpunk", "post punk"),
+    ("posthardcore", "post hardcore"),
+    ("shoegazer", "shoegaze"),
+    ("synth pop", "synthpop"),
+    ("electro pop", "electropop"),
+    ("kpop", "k pop"),
+    ("korean pop", "k pop"),
+    ("jpop", "j pop"),
+    ("japanese pop", "j pop"),
+    ("lofi", "lo fi"),
+    ("lo fi beats", "lo fi"),
+    ("lofi hip hop", "lo fi"),
+    ("chillhop", "lo fi"),
+    ("dnb", "drum and bass"),
+    ("d and b", "drum and bass"),
+    ("drum n bass", "drum and bass"),
+    ("triphop", "trip hop"),
+    ("neosoul", "neo soul"),
+    ("classical music", "classical"),
+    ("film score", "soundtrack"),
+    ("score", "soundtrack"),
+    ("ost", "soundtrack"),
+    ("video game", "video game music"),
+    ("vgm", "video game music"),
+    ("singer songwriters", "singer songwriter"),
+    ("altcountry", "alt country"),
+    ("numetal", "nu metal"),
+    ("metal core", "metalcore"),
+    ("death core", "deathcore"),
+    ("avantgarde", "avant garde"),
+    ("bossanova", "bossa nova"),
+    ("2 step", "uk garage"),
+    ("pov indie", "indie pop"),
+    // two genre names in one tag, without these the last word would win
+    ("punk rock", "punk"),
+    ("emo pop", "pop punk"),
+    ("rap music", "rap"),
+    ("jazz hip hop", "jazz rap"),
+    ("country folk", "americana"),
+    ("folk pop", "indie folk"),
+    ("electro house", "house"),
+    ("new jack swing", "contemporary r&b"),
+    ("western swing", "country"),
+];
+
+// names titlecase gets wrong, "R&b", "Edm" and "K Pop" otherwise
+const DISPLAY_NAMES: &[(&str, &str)] = &[
+    ("r&b", "R&B"),
+    ("contemporary r&b", "Contemporary R&B"),
+    ("alternative r&b", "Alternative R&B"),
+    ("edm", "EDM"),
+    ("idm", "IDM"),
+    ("k pop", "K-Pop"),
+    ("j pop", "J-Pop"),
+    ("uk garage", "UK Garage"),
+    ("lo fi", "Lo-Fi"),
+    ("post rock", "Post-Rock"),
+    ("post punk", "Post-Punk"),
+    ("post hardcore", "Post-Hardcore"),
+    ("trip hop", "Trip-Hop"),
+    ("singer songwriter", "Singer-Songwriter"),
+    ("alt country", "Alt-Country"),
+    ("avant garde", "Avant-Garde"),
+];
+
+// how a canonical genre is written on the slides
+pub fn display_name(genre: &str) -> String {
+    DISPLAY_NAMES
+        .iter()
+        .find(|(name, _)| *name == genre)
+        .map_or_else(|| titlecase(genre), |(_, display)| display.to_string())
+}
+
+// how broad the genres on the slides are, GENRE_GRANULARITY picks one
+#[derive(Clone, Copy, PartialEq)]
+pub enum GenreLevel {
+    Family,
+    Genre,
+    Subgenre,
+}
+
+impl GenreLevel {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "family" => Some(Self::Family),
+            "genre" => Some(Self::Genre),
+            "subgenre" => Some(Self::Subgenre),
+            _ => None,
+        }
+    }
+
+    // genre unless GENRE_GRANULARITY says otherwise
+    pub fn from_env() -> Self {
+        let _ = dotenvy::dotenv();
+        env::var("GENRE_GRANULARITY")
+            .ok()
+            .and_then(|l| Self::from_name(&l))
+            .unwrap_or(Self::Genre)
+    }
+
+    fn depth(self) -> usize {
+        match self {
+            Self::Family => 0,
+            Self::Genre => 1,
+            Self::Subgenre => 2,
+        }
+    }
+}
+
+pub struct Taxonomy {
+    // normalized name or alias -> canonical name
+    names: HashMap<String, &'static str>,
+    parents: HashMap<&'static str, &'static str>,
+}
+
+// lowercase words without punctuation, "Hip-Hop" and "hip hop" are the same genre
+fn normalize(genre: &str) -> String {
+    genre
+        .to_lowercase()
+        .replace('&', " and ")
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl Taxonomy {
+    fn new() -> Self {
+        let mut names = HashMap::new();
+        let mut parents = HashMap::new();
+        for (genre, parent) in GENRES {
+            names.insert(normalize(genre), *genre);
+            if let Some(parent) = parent {
+                parents.insert(*genre, *parent);
+            }
+        }
+        for (alias, genre) in ALIASES {
+            if let Some(canonical) = names.get(&normalize(genre)).copied() {
+                names.entry(normalize(alias)).or_insert(canonical);
+            }
+        }
+        Self { names, parents }
+    }
+
+    // the built in taxonomy, built once
+    pub fn get() -> &'static Taxonomy {
+        static TAXONOMY: OnceLock<Taxonomy> = OnceLock::new();
+        TAXONOMY.get_or_init(Taxonomy::new)
+    }
+
+    // "swedish indie pop" isnt listed but "indie pop" is, so words are dropped off the front
+    // until something matches
+    fn lookup(&self, genre: &str) -> Option<&'static str> {
+        let normalized = normalize(genre);
+        let words = normalized.split(' ').collect::<Vec<_>>();
+        (0..words.len()).find_map(|i| self.names.get(&words[i..].join(" ")).copied())
+    }
+
+    fn depth(&self, genre: &str) -> usize {
+        let mut depth = 0;
+        let mut current = genre;
+        while let Some(parent) = self.parents.get(current) {
+            depth += 1;
+            current = parent;
+        }
+        depth
+    }
+
+    // the canonical genre at level, genres nobody listed stay as they are, just normalized
+    pub fn canonicalize(&self, genre: &str, level: GenreLevel) -> String {
+        let mut current = match self.lookup(genre) {
+            Some(canonical) => canonical,
+            None => return normalize(genre),
+        };
+        while self.depth(current) > level.depth() {
+            current = self.parents[current];
+        }
+        current.to_string()
+    }
+
+    // every canonical genre for a list of raw ones, in the same order without duplicates
+    pub fn canonicalize_all(&self, genres: &[String], level: GenreLevel) -> Vec<String> {
+        let mut canonical = Vec::with_capacity(genres.len());
+        for genre in genres.iter().filter(|g| !g.is_empty()) {
+            let genre = self.canonicalize(genre, level);
+            if !genre.is_empty() && !canonical.contains(&genre) {
+                canonical.push(genre);
+            }
+        }
+        canonical
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canonical(genre: &str, level: GenreLevel) -> String {
+        Taxonomy::get().canonicalize(genre, level)
+    }
+
+    #[test]
+    fn canonicalizes_at_each_level() {
+        let cases = [
+            ("Math Rock", "rock", "indie rock", "math rock"),
+            ("midwest emo", "rock", "emo", "midwest emo"),
+            ("Hip-Hop", "hip hop", "hip hop", "hip hop"),
+            ("cloud rap", "hip hop", "rap", "cloud rap"),
+            ("deep house", "electronic", "house", "deep house"),
+            ("rnb", "r&b", "r&b", "r&b"),
+            ("swedish indie pop", "pop", "indie pop", "indie pop"),
+        ];
+        for (genre, family, mid, sub) in cases {
+            assert_eq!(canonical(genre, GenreLevel::Family), family, "{}", genre);
+            assert_eq!(canonical(genre, GenreLevel::Genre), mid, "{}", genre);
+            assert_eq!(canonical(genre, GenreLevel::Subgenre), sub, "{}", genre);
+        }
+    }
+
+    #[test]
+    fn compound_tags_keep_their_family() {
+        assert_eq!(canonical("punk rock", GenreLevel::Family), "punk");
+        assert_eq!(canonical("Punk Rock", GenreLevel::Genre), "punk");
+        assert_eq!(canonical("folk punk", GenreLevel::Subgenre), "folk punk");
+        assert_eq!(canonical("jazz rap", GenreLevel::Family), "hip hop");
+        assert_eq!(canonical("jazz rap", GenreLevel::Subgenre), "jazz rap");
+        assert_eq!(canonical("New Jack Swing", GenreLevel::Family), "r&b");
+        assert_eq!(canonical("western swing", GenreLevel::Family), "country");
+        assert_eq!(canonical("swing", GenreLevel::Family), "jazz");
+    }
+
+    #[test]
+    fn unknown_genres_are_only_normalized() {
+        assert_eq!(
+            canonical("Hyper-Specific Vibes", GenreLevel::Genre),
+            "hyper specific vibes"
+        );
+        assert_eq!(
+            Taxonomy::get().canonicalize_all(
+                &[
+                    "".to_string(),
+                    "indie".to_string(),
+                    "Indie Rock".to_string()
+                ],
+                GenreLevel::Genre
+            ),
+            vec!["indie rock"]
+        );
+    }
+
+    #[test]
+    fn display_names_keep_acronyms() {
+        assert_eq!(display_name("r&b"), "R&B");
+        assert_eq!(display_name("edm"), "EDM");
+        assert_eq!(display_name("k pop"), "K-Pop");
+        assert_eq!(display_name("indie rock"), "Indie Rock");
+    }
+}